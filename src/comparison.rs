@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+
+/// Summary of the differences between the levels of two scenarios
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Statistics {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// The mean of the absolute differences
+    pub mean_abs: f64,
+    /// The number of segments where the levels are different
+    pub changed: usize,
+}
+
+/// The differences between the levels of an alternative scenario and a baseline, segment by segment
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Comparison {
+    pub deltas: Vec<f64>,
+    pub statistics: Statistics,
+}
+
+/// Differences below this threshold are considered rounding errors rather than changes
+const DELTA_EPSILON: f64 = 1e-6;
+
+impl Comparison {
+    /// Compare the levels of both scenarios, which are expected to have the same number of segments
+    pub fn between(baseline: &[f64], alternative: &[f64]) -> Comparison {
+        let deltas: Vec<f64> = baseline
+            .iter()
+            .zip(alternative.iter())
+            .map(|(baseline, alternative)| alternative - baseline)
+            .collect();
+
+        let count = deltas.len().max(1) as f64;
+        let statistics = Statistics {
+            min: deltas.iter().cloned().reduce(f64::min).unwrap_or(0.0),
+            max: deltas.iter().cloned().reduce(f64::max).unwrap_or(0.0),
+            mean: deltas.iter().sum::<f64>() / count,
+            mean_abs: deltas.iter().map(|delta| delta.abs()).sum::<f64>() / count,
+            changed: deltas
+                .iter()
+                .filter(|delta| delta.abs() > DELTA_EPSILON)
+                .count(),
+        };
+
+        Comparison { deltas, statistics }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+    use crate::simulation::tests::assert_slice_approx_eq;
+
+    #[test]
+    fn comparison_between() {
+        let comparison = Comparison::between(&[1.0, 2.0, 3.0, 4.0], &[2.0, 2.0, 1.0, 4.0]);
+
+        assert_slice_approx_eq(comparison.deltas.as_slice(), &[1.0, 0.0, -2.0, 0.0]);
+        assert_approx_eq!(comparison.statistics.min, -2.0);
+        assert_approx_eq!(comparison.statistics.max, 1.0);
+        assert_approx_eq!(comparison.statistics.mean, -0.25);
+        assert_approx_eq!(comparison.statistics.mean_abs, 0.75);
+        assert_eq!(comparison.statistics.changed, 2);
+    }
+
+    #[test]
+    fn comparison_between_with_higher_levels() {
+        let comparison = Comparison::between(&[1.0, 2.0], &[2.0, 4.0]);
+
+        assert_approx_eq!(comparison.statistics.min, 1.0);
+        assert_approx_eq!(comparison.statistics.max, 2.0);
+    }
+
+    #[test]
+    fn comparison_between_empty() {
+        let comparison = Comparison::between(&[], &[]);
+
+        assert!(comparison.deltas.is_empty());
+        assert_approx_eq!(comparison.statistics.min, 0.0);
+        assert_approx_eq!(comparison.statistics.max, 0.0);
+        assert_approx_eq!(comparison.statistics.mean, 0.0);
+        assert_eq!(comparison.statistics.changed, 0);
+    }
+}
@@ -0,0 +1,66 @@
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use tungstenite::Message;
+
+/// The format of the messages sent to a client, which is negotiated per connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    /// Text messages with JSON, used by default
+    Json,
+    /// Binary messages with MessagePack, which are much smaller for long lists of levels
+    MessagePack,
+}
+
+impl Encoding {
+    /// The structs are encoded as maps in MessagePack, so the messages have the same shape as in JSON
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Message> {
+        match self {
+            Encoding::Json => Ok(Message::Text(serde_json::to_string(value)?)),
+            Encoding::MessagePack => Ok(Message::Binary(rmp_serde::to_vec_named(value)?)),
+        }
+    }
+}
+
+/// Decode a message into a generic value, with the encoding given by the kind of message:
+/// text messages are JSON, and binary messages are MessagePack.
+///
+/// The control messages of the WebSocket don't have any value.
+pub fn decode(message: &Message) -> Option<Result<Value>> {
+    match message {
+        Message::Text(text) => Some(serde_json::from_str(text).map_err(anyhow::Error::from)),
+        Message::Binary(bytes) => Some(rmp_serde::from_slice(bytes).map_err(anyhow::Error::from)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn encoding_round_trip() {
+        let value = json!({"event": "progress", "params": {"time": 0.5, "levels": [1.0, 2.5]}});
+
+        for encoding in [Encoding::Json, Encoding::MessagePack] {
+            let message = encoding.encode(&value).unwrap();
+            assert_eq!(decode(&message).unwrap().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn encoding_message_pack_is_binary() {
+        let message = Encoding::MessagePack.encode(&json!({"event": "pause"}));
+
+        assert!(matches!(message, Ok(Message::Binary(_))));
+    }
+
+    #[test]
+    fn decode_invalid_messages() {
+        assert!(decode(&Message::Text("{".to_string())).unwrap().is_err());
+        assert!(decode(&Message::Binary(vec![0xc1])).unwrap().is_err());
+        assert!(decode(&Message::Ping(vec![])).is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use std::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+use crate::numeric::Volume;
+
+const FRACTIONAL_BITS: u32 = 32;
+const FRACTIONAL_MASK: i128 = (1 << FRACTIONAL_BITS) - 1;
+
+/// A fixed-point number with 32 fractional bits.
+///
+/// All the operations are done with integer arithmetic, so the results are bit-exact
+/// independently of the hardware or the order in which the compiler decides to evaluate them.
+/// The integer part has enough bits to hold the capacity of the root sink for very large landscapes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct Fixed(i128);
+
+impl Fixed {
+    /// Integer square root by the Newton method
+    fn integer_sqrt(value: u128) -> u128 {
+        if value < 2 {
+            return value;
+        }
+        let mut current = value;
+        let mut next = (current + value / current) / 2;
+        while next < current {
+            current = next;
+            next = (current + value / current) / 2;
+        }
+        current
+    }
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+
+    #[inline]
+    fn add(self, rhs: Self) -> Self::Output {
+        Fixed(self.0 + rhs.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    #[inline]
+    fn add_assign(&mut self, rhs: Self) {
+        self.0 += rhs.0
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+
+    #[inline]
+    fn sub(self, rhs: Self) -> Self::Output {
+        Fixed(self.0 - rhs.0)
+    }
+}
+
+impl SubAssign for Fixed {
+    #[inline]
+    fn sub_assign(&mut self, rhs: Self) {
+        self.0 -= rhs.0
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+
+    /// The integer and fractional parts of the left operand are multiplied separately
+    /// to avoid overflowing the intermediate results.
+    #[inline]
+    fn mul(self, rhs: Self) -> Self::Output {
+        let integer = (self.0 >> FRACTIONAL_BITS) * rhs.0;
+        let fraction = ((self.0 & FRACTIONAL_MASK) * rhs.0) >> FRACTIONAL_BITS;
+        Fixed(integer + fraction)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+
+    /// The quotient and the remainder are scaled separately to avoid overflowing the intermediate results.
+    #[inline]
+    fn div(self, rhs: Self) -> Self::Output {
+        let quotient = self.0 / rhs.0;
+        let remainder = self.0 % rhs.0;
+        Fixed((quotient << FRACTIONAL_BITS) + (remainder << FRACTIONAL_BITS) / rhs.0)
+    }
+}
+
+impl Volume for Fixed {
+    const ZERO: Self = Fixed(0);
+    const ONE: Self = Fixed(1 << FRACTIONAL_BITS);
+    const MAX: Self = Fixed(i128::MAX);
+
+    #[inline]
+    fn from_f64(value: f64) -> Self {
+        Fixed((value * (1u64 << FRACTIONAL_BITS) as f64).round() as i128)
+    }
+
+    #[inline]
+    fn from_usize(value: usize) -> Self {
+        Fixed((value as i128) << FRACTIONAL_BITS)
+    }
+
+    #[inline]
+    fn to_f64(self) -> f64 {
+        self.0 as f64 / (1u64 << FRACTIONAL_BITS) as f64
+    }
+
+    fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            Fixed::ZERO
+        } else if self.0 < (1 << (127 - FRACTIONAL_BITS)) {
+            let raw = Self::integer_sqrt((self.0 as u128) << FRACTIONAL_BITS);
+            Fixed(raw as i128)
+        } else {
+            let raw = Self::integer_sqrt(self.0 as u128) << (FRACTIONAL_BITS / 2);
+            Fixed(raw as i128)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+
+    #[test]
+    fn fixed_from_and_to_f64() {
+        assert_eq!(Fixed::from_f64(1.0), Fixed::ONE);
+        assert_eq!(Fixed::from_f64(-2.5).to_f64(), -2.5);
+        assert_approx_eq!(Fixed::from_f64(0.1).to_f64(), 0.1, 1e-9);
+    }
+
+    #[test]
+    fn fixed_from_usize() {
+        assert_eq!(Fixed::from_usize(3).to_f64(), 3.0);
+        assert_eq!(
+            Fixed::from_usize(u32::MAX as usize).to_f64(),
+            u32::MAX as f64
+        );
+    }
+
+    #[test]
+    fn fixed_arithmetic() {
+        let a = Fixed::from_f64(7.5);
+        let b = Fixed::from_f64(2.0);
+
+        assert_eq!((a + b).to_f64(), 9.5);
+        assert_eq!((a - b).to_f64(), 5.5);
+        assert_eq!((a * b).to_f64(), 15.0);
+        assert_eq!((a / b).to_f64(), 3.75);
+        assert_eq!((b - a).to_f64(), -5.5);
+        assert_eq!((Fixed::from_f64(-1.5) * b).to_f64(), -3.0);
+    }
+
+    #[test]
+    fn fixed_arithmetic_with_large_values() {
+        let capacity = Fixed::from_usize(1_000_000) * Fixed::from_usize(u32::MAX as usize);
+
+        assert_eq!(capacity.to_f64(), 1_000_000.0 * u32::MAX as f64);
+        assert_eq!(
+            (capacity / Fixed::from_usize(1_000_000)).to_f64(),
+            u32::MAX as f64
+        );
+    }
+
+    #[test]
+    fn fixed_sqrt() {
+        assert_eq!(Fixed::from_f64(16.0).sqrt().to_f64(), 4.0);
+        assert_eq!(Fixed::from_f64(0.25).sqrt().to_f64(), 0.5);
+        assert_approx_eq!(Fixed::from_f64(2.0).sqrt().to_f64(), 2f64.sqrt(), 1e-9);
+        assert_eq!(Fixed::ZERO.sqrt(), Fixed::ZERO);
+    }
+}
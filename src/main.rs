@@ -1,8 +1,23 @@
+mod comparison;
+mod encoding;
+#[cfg(feature = "fixed-point")]
+mod fixed_point;
+mod numeric;
+mod outbox;
 mod protocol;
+mod recorder;
 mod simulation;
+mod subscription;
+mod sweep;
+mod view;
 mod water_flow;
 
+use std::fs::File;
+use std::io::{self, BufReader};
 use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::Result;
 use futures_channel::mpsc;
@@ -11,7 +26,11 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio_tungstenite::accept_async;
 use tungstenite::Error as WsError;
 
-use crate::{protocol::Protocol, simulation::Simulation};
+use crate::{
+    protocol::{Limits, Protocol},
+    recorder::{read_recording, replay, Recorder},
+    simulation::Simulation,
+};
 
 const FEEDBACK_CHANNEL_SIZE: usize = 1024;
 
@@ -19,25 +38,64 @@ const FEEDBACK_CHANNEL_SIZE: usize = 1024;
 async fn main() -> Result<()> {
     env_logger::init();
 
+    let args: Vec<String> = std::env::args().collect();
+    if let [_, command, path] = args.as_slice() {
+        if command == "replay" {
+            return replay_file(path).await;
+        }
+    }
+
     let port = std::env::var("PORT").unwrap_or_else(|_| "9002".to_string());
     let addr = format!("0.0.0.0:{}", port);
-    start_server(addr).await
+    start_server(addr, limits_from_env()?).await
+}
+
+/// The default limits of the simulations can be overridden with environment variables
+fn limits_from_env() -> Result<Limits> {
+    let defaults = Limits::default();
+    Ok(Limits {
+        max_segments: var_or("MAX_SEGMENTS", defaults.max_segments)?,
+        max_hours: var_or("MAX_HOURS", defaults.max_hours)?,
+        min_level: var_or("MIN_LEVEL", defaults.min_level)?,
+        max_level: var_or("MAX_LEVEL", defaults.max_level)?,
+        max_simulations: var_or("MAX_SIMULATIONS", defaults.max_simulations)?,
+        max_forward_work: var_or("MAX_FORWARD_WORK", defaults.max_forward_work)?,
+    })
+}
+
+fn var_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|err| anyhow::anyhow!("Invalid {}: {}", name, err)),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Replay a recording and write the new run to the standard output with the same format
+async fn replay_file<P: AsRef<Path>>(path: P) -> Result<()> {
+    let entries = read_recording(BufReader::new(File::open(path)?))?;
+    replay(entries, Recorder::new(io::stdout())).await
 }
 
-async fn start_server<S: AsRef<str>>(addr: S) -> Result<()> {
+async fn start_server<S: AsRef<str>>(addr: S, limits: Limits) -> Result<()> {
     let listener = TcpListener::bind(addr.as_ref()).await?;
     log::info!("Listening on: {}", addr.as_ref());
 
     while let Ok((stream, _)) = listener.accept().await {
         let peer = stream.peer_addr()?;
-        tokio::spawn(accept_connection(peer, stream));
+        tokio::spawn(accept_connection(peer, stream, limits));
     }
 
     Ok(())
 }
 
-async fn accept_connection(peer: SocketAddr, stream: TcpStream) {
-    if let Err(err) = handle_connection(peer, stream).await {
+async fn accept_connection(peer: SocketAddr, stream: TcpStream, limits: Limits) {
+    if let Err(err) = handle_connection(peer, stream, limits).await {
         if let Some(source) = err.source() {
             match source.downcast_ref::<WsError>() {
                 Some(WsError::ConnectionClosed)
@@ -49,7 +107,24 @@ async fn accept_connection(peer: SocketAddr, stream: TcpStream) {
     }
 }
 
-async fn handle_connection(peer: SocketAddr, stream: TcpStream) -> Result<()> {
+/// The addresses are written as `{ip}_{port}`, as the colons of IPv6 can not be used in the file names of every system
+fn recording_name(millis: u128, peer: SocketAddr) -> String {
+    let ip: String = peer
+        .ip()
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("{}-{}_{}.jsonl", millis, ip, peer.port())
+}
+
+async fn handle_connection(peer: SocketAddr, stream: TcpStream, limits: Limits) -> Result<()> {
     let messages = accept_async(stream).await?;
     log::info!("New WebSocket connection: {}", peer);
 
@@ -59,14 +134,33 @@ async fn handle_connection(peer: SocketAddr, stream: TcpStream) -> Result<()> {
 
     let simulation = Simulation::new();
 
-    Protocol::new(simulation)
-        .run(
-            outgoing_messages,
-            incoming_messages,
-            outgoing_feedback_loop,
-            incoming_feedback_loop,
-        )
-        .await
+    // The connections are recorded into a directory when RECORD_DIR is defined
+    if let Ok(dir) = std::env::var("RECORD_DIR") {
+        let millis = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis();
+        let path = Path::new(&dir).join(recording_name(millis, peer));
+        let recorder = Recorder::create(&path)?;
+        log::info!("Recording connection {} into {}", peer, path.display());
+
+        Protocol::new(simulation)
+            .with_limits(limits)
+            .run(
+                Box::pin(recorder.outgoing(outgoing_messages)),
+                Box::pin(recorder.incoming(incoming_messages)),
+                outgoing_feedback_loop,
+                incoming_feedback_loop,
+            )
+            .await
+    } else {
+        Protocol::new(simulation)
+            .with_limits(limits)
+            .run(
+                outgoing_messages,
+                incoming_messages,
+                outgoing_feedback_loop,
+                incoming_feedback_loop,
+            )
+            .await
+    }
 }
 
 #[cfg(test)]
@@ -79,7 +173,19 @@ mod tests {
     use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
 
     use super::*;
-    use crate::{protocol::Event, simulation::tests::assert_slice_approx_eq_with_epsilon};
+    use crate::{
+        protocol::{Event, StartOptions},
+        simulation::tests::assert_slice_approx_eq_with_epsilon,
+    };
+
+    #[test]
+    fn recording_name_of_the_addresses() {
+        let ipv4 = SocketAddr::from_str("127.0.0.1:9000").unwrap();
+        let ipv6 = SocketAddr::from_str("[fe80::1%2]:9000").unwrap();
+
+        assert_eq!(recording_name(5, ipv4), "5-127.0.0.1_9000.jsonl");
+        assert_eq!(recording_name(5, ipv6), "5-fe80--1_9000.jsonl");
+    }
 
     #[tokio::test]
     async fn successful_connection() {
@@ -88,6 +194,7 @@ mod tests {
                 .send(Event::Start {
                     hours: 1.0,
                     landscape: vec![1.0, 2.0],
+                    options: StartOptions::default(),
                 })
                 .await
                 .unwrap();
@@ -98,6 +205,7 @@ mod tests {
                     running,
                     time,
                     levels,
+                    ..
                 } = event
                 {
                     if !running {
@@ -127,7 +235,7 @@ mod tests {
     {
         let port: u16 = 9002;
         let addr = format!("127.0.0.1:{}", port);
-        tokio::spawn(start_server(addr.clone()));
+        tokio::spawn(start_server(addr.clone(), Limits::default()));
         tokio::time::sleep(Duration::from_secs(1)).await;
 
         let url = format!("ws://127.0.0.1:{}", port);
@@ -0,0 +1,290 @@
+use std::fmt::Debug;
+use std::ops::{Add, AddAssign, Div, Mul, Sub, SubAssign};
+
+/// This defines what is required from the types used to represent the levels of the terrain
+///
+/// The maximum value is used as the top level of the root sink, which is bounded by the infinite walls in the extremes.
+pub trait Height: Copy + Debug + PartialOrd {
+    const MIN: Self;
+    const MAX: Self;
+
+    fn to_f64(self) -> f64;
+
+    /// The infinite and NaN heights can not be ordered with the rest, so they are not valid for a landscape
+    #[inline]
+    fn is_finite(self) -> bool {
+        self.to_f64().is_finite()
+    }
+
+    #[inline]
+    fn highest(self, other: Self) -> Self {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+macro_rules! impl_height {
+    ($($ty:ty),*) => {
+        $(
+            impl Height for $ty {
+                const MIN: Self = <$ty>::MIN;
+                const MAX: Self = <$ty>::MAX;
+
+                #[inline]
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+            }
+        )*
+    };
+}
+
+impl_height!(u8, u16, u32, f32, f64);
+
+/// This defines the arithmetic required from the types used to represent volumes of water
+///
+/// The simulation of the water flow is generic over it, so that the floating point numbers
+/// can be replaced by other representations with different precision or reproducibility guarantees.
+pub trait Volume:
+    Copy
+    + Debug
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    const ZERO: Self;
+    const ONE: Self;
+    const MAX: Self;
+
+    fn from_f64(value: f64) -> Self;
+
+    fn from_usize(value: usize) -> Self;
+
+    fn to_f64(self) -> f64;
+
+    /// The square root is approximated with floating point numbers by default,
+    /// as some exact representations (like rationals) don't have one.
+    #[inline]
+    fn sqrt(self) -> Self {
+        Self::from_f64(self.to_f64().sqrt())
+    }
+
+    #[inline]
+    fn from_height<H: Height>(height: H) -> Self {
+        Self::from_f64(height.to_f64())
+    }
+
+    #[inline]
+    fn min(self, other: Self) -> Self {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+macro_rules! impl_float_volume {
+    ($($ty:ident),*) => {
+        $(
+            impl Volume for $ty {
+                const ZERO: Self = 0.0;
+                const ONE: Self = 1.0;
+                const MAX: Self = $ty::MAX;
+
+                #[inline]
+                fn from_f64(value: f64) -> Self {
+                    value as $ty
+                }
+
+                #[inline]
+                fn from_usize(value: usize) -> Self {
+                    value as $ty
+                }
+
+                #[inline]
+                fn to_f64(self) -> f64 {
+                    self as f64
+                }
+
+                #[inline]
+                fn sqrt(self) -> Self {
+                    $ty::sqrt(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_float_volume!(f32, f64);
+
+#[cfg(test)]
+pub mod tests {
+    use std::cmp::Ordering;
+
+    use super::*;
+
+    /// An exact rational number, which allows to check the simulation of the water flow without rounding errors.
+    ///
+    /// The conversions from floating point numbers are rounded to a fixed denominator,
+    /// which is exact for the values with few fractional digits used in the tests.
+    #[derive(Clone, Copy, Debug)]
+    pub struct Rational {
+        numerator: i128,
+        denominator: i128,
+    }
+
+    const DENOMINATOR: i128 = 1 << 20;
+
+    fn gcd(a: i128, b: i128) -> i128 {
+        if b == 0 {
+            a.abs()
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    impl Rational {
+        pub fn new(numerator: i128, denominator: i128) -> Self {
+            let divisor = gcd(numerator, denominator) * denominator.signum();
+            Rational {
+                numerator: numerator / divisor,
+                denominator: denominator / divisor,
+            }
+        }
+
+        fn cross(self, other: Self) -> (i128, i128) {
+            (
+                self.numerator.checked_mul(other.denominator).unwrap(),
+                other.numerator.checked_mul(self.denominator).unwrap(),
+            )
+        }
+    }
+
+    impl PartialEq for Rational {
+        fn eq(&self, other: &Self) -> bool {
+            self.numerator == other.numerator && self.denominator == other.denominator
+        }
+    }
+
+    impl PartialOrd for Rational {
+        /// The maximum value can not be cross multiplied, so it is compared by its integer part
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            if *self == Self::MAX || *other == Self::MAX {
+                let floor = |value: &Self| value.numerator.div_euclid(value.denominator);
+                return floor(self).partial_cmp(&floor(other));
+            }
+            let (left, right) = self.cross(*other);
+            left.partial_cmp(&right)
+        }
+    }
+
+    impl Add for Rational {
+        type Output = Rational;
+
+        fn add(self, rhs: Self) -> Self::Output {
+            let (left, right) = self.cross(rhs);
+            let denominator = self.denominator.checked_mul(rhs.denominator).unwrap();
+            Rational::new(left.checked_add(right).unwrap(), denominator)
+        }
+    }
+
+    impl Sub for Rational {
+        type Output = Rational;
+
+        fn sub(self, rhs: Self) -> Self::Output {
+            let (left, right) = self.cross(rhs);
+            let denominator = self.denominator.checked_mul(rhs.denominator).unwrap();
+            Rational::new(left.checked_sub(right).unwrap(), denominator)
+        }
+    }
+
+    impl Mul for Rational {
+        type Output = Rational;
+
+        fn mul(self, rhs: Self) -> Self::Output {
+            let left = Rational::new(self.numerator, rhs.denominator);
+            let right = Rational::new(rhs.numerator, self.denominator);
+            Rational::new(
+                left.numerator.checked_mul(right.numerator).unwrap(),
+                left.denominator.checked_mul(right.denominator).unwrap(),
+            )
+        }
+    }
+
+    impl Div for Rational {
+        type Output = Rational;
+
+        fn div(self, rhs: Self) -> Self::Output {
+            let (numerator, denominator) = self.cross(rhs);
+            Rational::new(numerator, denominator)
+        }
+    }
+
+    impl AddAssign for Rational {
+        fn add_assign(&mut self, rhs: Self) {
+            *self = *self + rhs;
+        }
+    }
+
+    impl SubAssign for Rational {
+        fn sub_assign(&mut self, rhs: Self) {
+            *self = *self - rhs;
+        }
+    }
+
+    impl Volume for Rational {
+        const ZERO: Self = Rational {
+            numerator: 0,
+            denominator: 1,
+        };
+        const ONE: Self = Rational {
+            numerator: 1,
+            denominator: 1,
+        };
+        const MAX: Self = Rational {
+            numerator: i128::MAX,
+            denominator: 1,
+        };
+
+        fn from_f64(value: f64) -> Self {
+            Rational::new((value * DENOMINATOR as f64).round() as i128, DENOMINATOR)
+        }
+
+        fn from_usize(value: usize) -> Self {
+            Rational::new(value as i128, 1)
+        }
+
+        fn to_f64(self) -> f64 {
+            self.numerator as f64 / self.denominator as f64
+        }
+    }
+
+    #[test]
+    fn rational_arithmetic() {
+        let a = Rational::new(3, 4);
+        let b = Rational::new(1, 6);
+
+        assert_eq!(a + b, Rational::new(11, 12));
+        assert_eq!(a - b, Rational::new(7, 12));
+        assert_eq!(a * b, Rational::new(1, 8));
+        assert_eq!(a / b, Rational::new(9, 2));
+        assert_eq!(Rational::new(2, -4), Rational::new(-1, 2));
+        assert!(b < a && a < Rational::MAX);
+    }
+
+    #[test]
+    fn height_is_finite() {
+        assert!(Height::is_finite(7u32));
+        assert!(Height::is_finite(2.5f64));
+        assert!(!Height::is_finite(f64::NAN));
+        assert!(!Height::is_finite(f32::INFINITY));
+    }
+}
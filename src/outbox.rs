@@ -0,0 +1,535 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::Infallible;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use futures::task::AtomicWaker;
+use futures_util::{stream::Stream, Sink};
+
+use crate::protocol::{Envelope, Event};
+use crate::simulation::StepReason;
+
+/// Create the queue of outgoing events of a connection, which decouples the protocol from a slow client.
+///
+/// When the queue reaches its capacity, or the size of its events reaches the maximum size, the new progress events
+/// are merged with the ones that are still waiting in the queue for the same simulation, and the client is told how many were dropped.
+/// The rest of events are queued up to twice the capacity and the maximum size, and then the sender has to wait,
+/// so the memory never grows without limit. The size is counted in the numbers (like levels) carried by the events,
+/// as a single progress of a large landscape can take megabytes.
+pub fn outbox(capacity: usize, max_size: usize) -> (OutboxSender, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            events: VecDeque::new(),
+            capacity,
+            size: 0,
+            max_size,
+            closed: false,
+        }),
+        sender: AtomicWaker::new(),
+        receiver: AtomicWaker::new(),
+    });
+    (
+        OutboxSender {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+pub struct OutboxSender {
+    shared: Arc<Shared>,
+}
+
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    sender: AtomicWaker,
+    receiver: AtomicWaker,
+}
+
+struct Queue {
+    events: VecDeque<Queued>,
+    capacity: usize,
+    /// The size of all the queued events
+    size: usize,
+    max_size: usize,
+    closed: bool,
+}
+
+struct Queued {
+    envelope: Envelope,
+    size: usize,
+    /// The number of older events merged into this one
+    dropped: usize,
+}
+
+/// The kinds of events that can be merged, as only the latest one matters
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    Progress,
+    Lakes,
+    Sweep,
+}
+
+impl Kind {
+    fn of(event: &Event) -> Option<Kind> {
+        match event {
+            Event::Progress { .. }
+            | Event::ProgressDelta { .. }
+            | Event::ViewProgress { .. }
+            | Event::ComparisonProgress { .. } => Some(Kind::Progress),
+            Event::Lakes { .. } => Some(Kind::Lakes),
+            Event::SweepProgress { .. } => Some(Kind::Sweep),
+            _ => None,
+        }
+    }
+}
+
+/// The approximate size of an event, counted in the numbers that it carries
+fn size(event: &Event) -> usize {
+    let numbers = match event {
+        Event::Progress { levels, .. } => levels.len(),
+        Event::ProgressDelta {
+            indices, values, ..
+        } => indices.len() + values.len(),
+        Event::ViewProgress { buckets, .. } => buckets.len() * 3,
+        Event::ComparisonProgress {
+            levels,
+            alternative_levels,
+            deltas,
+            ..
+        } => levels.len() + alternative_levels.len() + deltas.len(),
+        Event::Lakes { lakes, .. } => lakes.len() * 8,
+        Event::SweepResult { runs } => runs
+            .iter()
+            .map(|run| run.peak_depths.len() + run.final_levels.len())
+            .sum(),
+        Event::SnapshotData { snapshot } => snapshot.landscape.len(),
+        _ => 0,
+    };
+    numbers + 1
+}
+
+impl Queue {
+    fn is_full(&self) -> bool {
+        self.events.len() >= self.capacity.saturating_mul(2)
+            || self.size >= self.max_size.saturating_mul(2)
+    }
+
+    fn pop(&mut self) -> Option<Queued> {
+        let queued = self.events.pop_front()?;
+        self.size -= queued.size;
+        Some(queued)
+    }
+
+    fn push(&mut self, envelope: Envelope) {
+        let kind = Kind::of(&envelope.event);
+        let crowded =
+            self.events.len() >= self.capacity || self.size + size(&envelope.event) > self.max_size;
+        let position = if kind.is_some() && crowded {
+            self.events.iter().rposition(|queued| {
+                queued.envelope.simulation == envelope.simulation
+                    && Kind::of(&queued.envelope.event) == kind
+                    && can_merge(&queued.envelope, &envelope)
+            })
+        } else {
+            None
+        };
+
+        let (envelope, dropped) = match position.and_then(|position| self.events.remove(position)) {
+            Some(older) => {
+                self.size -= older.size;
+                (merge(older.envelope, envelope), older.dropped + 1)
+            }
+            None => (envelope, 0),
+        };
+        let size = size(&envelope.event);
+        self.size += size;
+        self.events.push_back(Queued {
+            envelope,
+            size,
+            dropped,
+        });
+    }
+}
+
+/// The events are merged when the newer one has all the information, or when it is a delta that can be applied to the older one.
+/// The request ids are kept, so at most one of them can be caused by a request.
+fn can_merge(older: &Envelope, newer: &Envelope) -> bool {
+    let ids = older.id.is_none() || newer.id.is_none() || older.id == newer.id;
+    let events = match (&older.event, &newer.event) {
+        (Event::Progress { .. }, Event::ProgressDelta { .. })
+        | (Event::ProgressDelta { .. }, Event::ProgressDelta { .. }) => true,
+        (_, Event::ProgressDelta { .. }) => false,
+        _ => true,
+    };
+    ids && events
+}
+
+/// The reason of a dropped frame is kept when the newer one has none, as every reason is only reported once
+fn merge(mut older: Envelope, newer: Envelope) -> Envelope {
+    let id = newer.id.or(older.id);
+    let older_reason = reason_mut(&mut older.event).and_then(|reason| *reason);
+    let mut event = match (older.event, newer.event) {
+        (
+            Event::Progress { mut levels, .. },
+            Event::ProgressDelta {
+                running,
+                time,
+                indices,
+                values,
+                reason,
+            },
+        ) => {
+            for (index, value) in indices.into_iter().zip(values) {
+                if let Some(level) = levels.get_mut(index) {
+                    *level = value;
+                }
+            }
+            Event::Progress {
+                running,
+                time,
+                levels,
+                reason,
+            }
+        }
+        (
+            Event::ProgressDelta {
+                indices: older_indices,
+                values: older_values,
+                ..
+            },
+            Event::ProgressDelta {
+                running,
+                time,
+                indices: newer_indices,
+                values: newer_values,
+                reason,
+            },
+        ) => {
+            // The newer values replace the older ones of the same segments
+            let changes: BTreeMap<usize, f64> = older_indices
+                .into_iter()
+                .zip(older_values)
+                .chain(newer_indices.into_iter().zip(newer_values))
+                .collect();
+            let (indices, values) = changes.into_iter().unzip();
+            Event::ProgressDelta {
+                running,
+                time,
+                indices,
+                values,
+                reason,
+            }
+        }
+        (_, newer) => newer,
+    };
+    if let Some(reason) = reason_mut(&mut event) {
+        *reason = reason.or(older_reason);
+    }
+    Envelope::new(newer.simulation, event).with_id(id)
+}
+
+fn reason_mut(event: &mut Event) -> Option<&mut Option<StepReason>> {
+    match event {
+        Event::Progress { reason, .. }
+        | Event::ProgressDelta { reason, .. }
+        | Event::ViewProgress { reason, .. }
+        | Event::ComparisonProgress { reason, .. } => Some(reason),
+        _ => None,
+    }
+}
+
+impl Sink<Envelope> for OutboxSender {
+    type Error = Infallible;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared.sender.register(cx.waker());
+        if self.shared.queue.lock().unwrap().is_full() {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, envelope: Envelope) -> Result<(), Self::Error> {
+        self.shared.queue.lock().unwrap().push(envelope);
+        self.shared.receiver.wake();
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.receiver.wake();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for OutboxSender {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.receiver.wake();
+    }
+}
+
+impl Stream for OutboxReceiver {
+    type Item = Envelope;
+
+    /// The number of dropped events is reported just before the event they were merged into
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.shared.receiver.register(cx.waker());
+        let mut queue = self.shared.queue.lock().unwrap();
+        let next = match queue.events.front_mut() {
+            Some(queued) if queued.dropped > 0 => {
+                let frames = std::mem::take(&mut queued.dropped);
+                let dropped = Event::Dropped { frames };
+                Some(Envelope::new(queued.envelope.simulation.clone(), dropped))
+            }
+            Some(_) => queue.pop().map(|queued| queued.envelope),
+            None => None,
+        };
+        let closed = queue.closed;
+        drop(queue);
+
+        match next {
+            Some(envelope) => {
+                self.shared.sender.wake();
+                Poll::Ready(Some(envelope))
+            }
+            None if closed => Poll::Ready(None),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::protocol::SimulationId;
+
+    const MAX_SIZE: usize = 1000;
+
+    fn progress(time: f64, levels: Vec<f64>) -> Envelope {
+        Envelope::new(
+            SimulationId::default(),
+            Event::Progress {
+                running: true,
+                time,
+                levels,
+                reason: None,
+            },
+        )
+    }
+
+    fn delta(time: f64, indices: Vec<usize>, values: Vec<f64>) -> Envelope {
+        Envelope::new(
+            SimulationId::default(),
+            Event::ProgressDelta {
+                running: true,
+                time,
+                indices,
+                values,
+                reason: None,
+            },
+        )
+    }
+
+    async fn drain(sender: OutboxSender, receiver: OutboxReceiver) -> Vec<Event> {
+        drop(sender);
+        receiver.map(|envelope| envelope.event).collect().await
+    }
+
+    #[tokio::test]
+    async fn outbox_keeps_the_events_below_the_capacity() {
+        let (mut sender, receiver) = outbox(2, MAX_SIZE);
+
+        sender.send(progress(0.1, vec![1.0])).await.unwrap();
+        sender.send(progress(0.2, vec![2.0])).await.unwrap();
+
+        let events = drain(sender, receiver).await;
+        assert_eq!(
+            events,
+            vec![
+                progress(0.1, vec![1.0]).event,
+                progress(0.2, vec![2.0]).event
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn outbox_merges_the_progress_above_the_capacity() {
+        let (mut sender, receiver) = outbox(2, MAX_SIZE);
+
+        sender.send(progress(0.1, vec![1.0])).await.unwrap();
+        sender
+            .send(Envelope::new(SimulationId::default(), Event::Pause))
+            .await
+            .unwrap();
+        sender.send(progress(0.2, vec![2.0])).await.unwrap();
+        sender.send(progress(0.3, vec![3.0])).await.unwrap();
+
+        let events = drain(sender, receiver).await;
+        assert_eq!(
+            events,
+            vec![
+                Event::Pause,
+                Event::Dropped { frames: 2 },
+                progress(0.3, vec![3.0]).event
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn outbox_merges_the_progress_above_the_maximum_size() {
+        let (mut sender, mut receiver) = outbox(64, 4);
+
+        sender.send(progress(0.1, vec![1.0, 1.0])).await.unwrap();
+        sender.send(progress(0.2, vec![2.0, 2.0])).await.unwrap();
+        sender
+            .send(Envelope::new(
+                SimulationId::default(),
+                Event::SweepProgress {
+                    completed: 1,
+                    total: 2,
+                },
+            ))
+            .await
+            .unwrap();
+        sender
+            .send(Envelope::new(
+                "other".to_string(),
+                progress(0.3, vec![3.0; 8]).event,
+            ))
+            .await
+            .unwrap();
+        let blocked = futures::poll!(sender.send(progress(0.4, vec![4.0, 4.0])));
+        assert!(blocked.is_pending());
+
+        assert_eq!(
+            receiver.next().await.map(|envelope| envelope.event),
+            Some(Event::Dropped { frames: 1 })
+        );
+        assert_eq!(
+            receiver.next().await.map(|envelope| envelope.event),
+            Some(progress(0.2, vec![2.0, 2.0]).event)
+        );
+    }
+
+    #[tokio::test]
+    async fn outbox_merges_the_deltas() {
+        let (mut sender, receiver) = outbox(1, MAX_SIZE);
+
+        sender
+            .send(progress(0.1, vec![1.0, 1.0, 1.0]))
+            .await
+            .unwrap();
+        sender
+            .send(delta(0.2, vec![0, 2], vec![2.0, 2.0]))
+            .await
+            .unwrap();
+        sender
+            .send(delta(0.3, vec![1, 2], vec![3.0, 3.0]))
+            .await
+            .unwrap();
+
+        let events = drain(sender, receiver).await;
+        assert_eq!(
+            events,
+            vec![
+                Event::Dropped { frames: 2 },
+                progress(0.3, vec![2.0, 3.0, 3.0]).event
+            ]
+        );
+    }
+
+    #[test]
+    fn outbox_merges_deltas_into_deltas() {
+        let merged = merge(
+            delta(0.2, vec![0, 2], vec![2.0, 2.0]),
+            delta(0.3, vec![1, 2], vec![3.0, 3.0]),
+        );
+
+        assert_eq!(merged, delta(0.3, vec![0, 1, 2], vec![2.0, 3.0, 3.0]));
+    }
+
+    #[test]
+    fn outbox_keeps_the_reasons() {
+        let mut older = progress(0.1, vec![1.0]);
+        if let Event::Progress { reason, .. } = &mut older.event {
+            *reason = Some(StepReason::SinkFilled);
+        }
+
+        let merged = merge(older, delta(0.2, vec![0], vec![2.0]));
+
+        assert_eq!(
+            merged.event,
+            Event::Progress {
+                running: true,
+                time: 0.2,
+                levels: vec![2.0],
+                reason: Some(StepReason::SinkFilled),
+            }
+        );
+
+        let mut newer = progress(0.3, vec![3.0]);
+        if let Event::Progress { reason, .. } = &mut newer.event {
+            *reason = Some(StepReason::LakesMerged);
+        }
+
+        let merged = merge(merged, newer);
+
+        assert_eq!(
+            merged.event,
+            Event::Progress {
+                running: true,
+                time: 0.3,
+                levels: vec![3.0],
+                reason: Some(StepReason::LakesMerged),
+            }
+        );
+    }
+
+    #[test]
+    fn outbox_keeps_the_request_ids() {
+        let older = progress(0.1, vec![1.0]).with_id(Some("1".to_string()));
+        let newer = progress(0.2, vec![2.0]);
+        assert!(can_merge(&older, &newer));
+        assert_eq!(merge(older, newer).id, Some("1".to_string()));
+
+        let older = progress(0.1, vec![1.0]).with_id(Some("1".to_string()));
+        let newer = progress(0.2, vec![2.0]).with_id(Some("2".to_string()));
+        assert!(!can_merge(&older, &newer));
+    }
+
+    #[tokio::test]
+    async fn outbox_waits_when_full() {
+        let (mut sender, mut receiver) = outbox(1, MAX_SIZE);
+
+        for _ in 0..2 {
+            sender
+                .send(Envelope::new(SimulationId::default(), Event::Pause))
+                .await
+                .unwrap();
+        }
+        let blocked =
+            futures::poll!(sender.send(Envelope::new(SimulationId::default(), Event::Pause)));
+        assert!(blocked.is_pending());
+
+        receiver.next().await;
+        sender
+            .send(Envelope::new(SimulationId::default(), Event::Pause))
+            .await
+            .unwrap();
+        assert_eq!(drain(sender, receiver).await.len(), 2);
+    }
+}
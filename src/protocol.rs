@@ -1,45 +1,718 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 
-use anyhow::Result;
-use futures::stream;
-use futures::{StreamExt, TryStreamExt};
+use anyhow::{ensure, Result};
+use futures::{future, stream};
+use futures::{StreamExt, TryFutureExt, TryStreamExt};
+use futures_channel::mpsc;
 use futures_util::{stream::Stream, Sink, SinkExt};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use tokio::time::sleep;
 use tungstenite::{Error as WsError, Message};
 
-use crate::simulation::Simulation;
+use crate::comparison::{Comparison, Statistics};
+use crate::encoding::{decode, Encoding};
+use crate::outbox::outbox;
+use crate::simulation::{
+    Phase, Simulation, Snapshot, StepReason, DELTA_TIME, EVENT_TIME_TOLERANCE, MIN_PHASE_HOURS,
+};
+use crate::subscription::Subscription;
+use crate::sweep::{run_sweep, RunSummary, SweepParameters};
+use crate::view::{Bucket, View};
+use crate::water_flow::Lake;
 
 const FORWARD_HOURS: f64 = 1.0;
 const STEP_DELAY_MILLIS: u64 = 200;
 
+/// The version of the protocol implemented by the server.
+/// The clients that don't start with a hello use the unversioned protocol, which is still supported.
+const PROTOCOL_VERSION: u32 = 1;
+const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// The optional features of the protocol supported by the server
+const CAPABILITIES: &[&str] = &[
+    "errors",
+    "request_ids",
+    "simulations",
+    "comparison",
+    "sweep",
+    "snapshots",
+    "msgpack",
+    "delta",
+    "view",
+    "subscribe",
+];
+
+/// The names of the events, which tell the unknown events from the ones with invalid parameters
+const EVENT_NAMES: &[&str] = &[
+    "hello",
+    "start",
+    "step",
+    "progress",
+    "progressdelta",
+    "viewprogress",
+    "lakes",
+    "dropped",
+    "compare",
+    "comparisonprogress",
+    "pause",
+    "resume",
+    "forward",
+    "forwardstep",
+    "rewind",
+    "stepback",
+    "setspeed",
+    "seek",
+    "setview",
+    "clearview",
+    "close",
+    "extend",
+    "sweep",
+    "sweepprogress",
+    "sweepresult",
+    "snapshot",
+    "snapshotdata",
+    "restore",
+    "subscribe",
+    "error",
+];
+
+const KEYFRAME_INTERVAL: usize = 25;
+
+/// The number of outgoing events that can wait for a slow client before the progress events are merged
+const OUTBOX_CAPACITY: usize = 64;
+/// The numbers (like levels) of the outgoing events that can wait for a slow client before the progress events are merged,
+/// which take about 8 MB
+const OUTBOX_MAX_SIZE: usize = 1 << 20;
+
+/// The shortest step, so a fast forward step (even after changing its speed) simulates at most
+/// `MAX_FORWARD_HOURS / MIN_DELTA_TIME` steps
+const MIN_DELTA_TIME: f64 = 0.01;
+const MAX_DELTA_TIME: f64 = 24.0;
+const MAX_FORWARD_HOURS: f64 = 24.0 * 7.0;
+/// The maximum number of phases of a schedule, which are walked to find out the rain of the last cycle
+const MAX_PHASES: usize = 1000;
+const MIN_STEP_DELAY_MILLIS: u64 = 10;
+const MAX_STEP_DELAY_MILLIS: u64 = 10_000;
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "event", content = "params", rename_all = "lowercase")]
 pub enum Event {
+    /// Sent by the client at the start of the connection with the latest version that it supports,
+    /// and answered by the server with the negotiated version and the capabilities supported by both.
+    Hello {
+        protocol_version: u32,
+        #[serde(default)]
+        capabilities: Vec<String>,
+    },
     Start {
         landscape: Vec<f64>,
         hours: f64,
+        #[serde(flatten)]
+        options: StartOptions,
     },
     Step,
+    /// The levels can be left out by the subscription of the client
     Progress {
         running: bool,
         time: f64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        levels: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<StepReason>,
+    },
+    /// The levels of the segments that changed since the last progress, in the delta mode
+    ProgressDelta {
+        running: bool,
+        time: f64,
+        indices: Vec<usize>,
+        values: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<StepReason>,
+    },
+    /// The levels of the visible window reduced to buckets, when a view was set
+    ViewProgress {
+        running: bool,
+        time: f64,
+        start: usize,
+        end: usize,
+        buckets: Vec<Bucket>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<StepReason>,
+    },
+    Lakes {
+        time: f64,
+        lakes: Vec<Lake>,
+    },
+    /// Some progress events were merged into the next one, as the client was not receiving them fast enough
+    Dropped {
+        frames: usize,
+    },
+    Compare {
+        landscape: Vec<f64>,
+        alternative: Vec<f64>,
+        hours: f64,
+        #[serde(flatten)]
+        options: StartOptions,
+    },
+    ComparisonProgress {
+        running: bool,
+        time: f64,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
         levels: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        alternative_levels: Vec<f64>,
+        /// The differences between the levels of the alternative and the baseline
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        deltas: Vec<f64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        statistics: Option<Statistics>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        reason: Option<StepReason>,
     },
     Pause,
     Resume,
     Forward,
     ForwardStep,
+    Rewind,
+    StepBack,
+    SetSpeed {
+        step_delay_millis: Option<u64>,
+        forward_hours: Option<f64>,
+    },
+    Seek {
+        time: f64,
+    },
+    /// Pan or zoom the visible window without restarting the simulation
+    SetView {
+        #[serde(flatten)]
+        view: View,
+    },
+    /// Go back to the progress with all the levels
+    ClearView,
+    /// Remove a simulation of the connection, so its memory is released
+    Close,
+    Extend {
+        hours: f64,
+    },
+    Sweep {
+        #[serde(flatten)]
+        parameters: SweepParameters,
+    },
+    SweepProgress {
+        completed: usize,
+        total: usize,
+    },
+    SweepResult {
+        runs: Vec<RunSummary>,
+    },
+    /// Request a snapshot of the simulation, without the settings of the scenario like the speed or the view
+    Snapshot,
+    SnapshotData {
+        snapshot: Snapshot,
+    },
+    Restore {
+        snapshot: Snapshot,
+    },
+    /// Choose the events and the fields of the progress sent to the client, which confirms it with the same event
+    Subscribe {
+        #[serde(flatten)]
+        subscription: Subscription,
+    },
+    /// A request of the client was rejected, and `request_id` is the `id` of that request
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
 }
 
-pub struct Protocol {
+/// The kind of problem found with a request of the client
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The message is not valid JSON
+    ParseError,
+    /// The event is not known, or it can not be sent by the clients
+    UnknownEvent,
+    /// The parameters of the event are missing or not valid
+    InvalidParams,
+    /// The event can not be processed in the current state of the simulation
+    InvalidState,
+    /// The server failed to answer
+    Internal,
+}
+
+/// The reason why a request was rejected, which is reported to the client with an error event
+#[derive(Debug)]
+struct Rejection {
+    code: ErrorCode,
+    message: String,
+}
+
+impl Rejection {
+    fn new<M: ToString>(code: ErrorCode, message: M) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn into_envelope(self, simulation: SimulationId, request_id: Option<String>) -> Envelope {
+        let error = Event::Error {
+            code: self.code,
+            message: self.message,
+            request_id: request_id.clone(),
+        };
+        Envelope::new(simulation, error).with_id(request_id)
+    }
+}
+
+/// Where an event comes from, which is the client or the feedback loop.
+/// The steps of the feedback loop keep the generation of the scenario that scheduled them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+    Client,
+    Feedback(Option<u64>),
+}
+
+/// The events received by the protocol, depending on where they come from
+#[derive(Debug)]
+enum Received {
+    Request(Envelope),
+    Feedback(Envelope),
+    /// A message that could not be parsed, with as much information as it was possible to recover
+    Invalid {
+        simulation: SimulationId,
+        request_id: Option<String>,
+        rejection: Rejection,
+    },
+}
+
+/// Optional settings for the Start event
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StartOptions {
+    /// Report the lakes after every progress
+    pub lakes: bool,
+    /// Phases of rain and dry weather repeated cyclically
+    pub schedule: Vec<Phase>,
+    /// Depth of water per hour lost by outflow, evaporation or infiltration
+    pub loss_rate: f64,
+    /// Simulated hours for every step, or the maximum hours in the adaptive mode
+    pub delta_time: Option<f64>,
+    /// Move the clock forward to the next event of the hierarchy of sinks
+    pub adaptive: bool,
+    /// Real time between steps when playing the simulation
+    pub step_delay_millis: Option<u64>,
+    /// Simulated hours for every step when fast forwarding the simulation
+    pub forward_hours: Option<f64>,
+    /// Send only the levels that changed more than this threshold since they were last sent
+    pub delta_threshold: Option<f64>,
+    /// Number of progress events from a keyframe with all the levels to the next one in the delta mode
+    pub keyframe_interval: Option<usize>,
+}
+
+impl StartOptions {
+    fn validate(&self) -> Result<()> {
+        if let Some(delta_time) = self.delta_time {
+            ensure!(
+                (MIN_DELTA_TIME..=MAX_DELTA_TIME).contains(&delta_time),
+                "delta_time must be in the range [{}, {}]",
+                MIN_DELTA_TIME,
+                MAX_DELTA_TIME
+            );
+        }
+        ensure!(
+            self.loss_rate >= 0.0 && self.loss_rate.is_finite(),
+            "loss_rate must be finite and can not be negative"
+        );
+        ensure!(
+            self.schedule.len() <= MAX_PHASES,
+            "the schedule can not have more than {} phases",
+            MAX_PHASES
+        );
+        ensure!(
+            self.schedule
+                .iter()
+                .all(|phase| phase.hours >= MIN_PHASE_HOURS && phase.hours.is_finite()),
+            "the phases of the schedule must last at least {} hours",
+            MIN_PHASE_HOURS
+        );
+        if let Some(delta_threshold) = self.delta_threshold {
+            ensure!(
+                delta_threshold >= 0.0 && delta_threshold.is_finite(),
+                "delta_threshold can not be negative"
+            );
+        }
+        if let Some(keyframe_interval) = self.keyframe_interval {
+            ensure!(keyframe_interval > 0, "keyframe_interval must be positive");
+        }
+        validate_speed(self.step_delay_millis, self.forward_hours)
+    }
+}
+
+fn validate_speed(step_delay_millis: Option<u64>, forward_hours: Option<f64>) -> Result<()> {
+    if let Some(step_delay_millis) = step_delay_millis {
+        ensure!(
+            (MIN_STEP_DELAY_MILLIS..=MAX_STEP_DELAY_MILLIS).contains(&step_delay_millis),
+            "step_delay_millis must be in the range [{}, {}]",
+            MIN_STEP_DELAY_MILLIS,
+            MAX_STEP_DELAY_MILLIS
+        );
+    }
+    if let Some(forward_hours) = forward_hours {
+        ensure!(
+            forward_hours > 0.0 && forward_hours <= MAX_FORWARD_HOURS,
+            "forward_hours must be in the range (0, {}]",
+            MAX_FORWARD_HOURS
+        );
+    }
+    Ok(())
+}
+
+/// Both scenarios need to be compared segment by segment at the same times,
+/// so the adaptive mode, which depends on the events of each landscape, is not supported.
+fn validate_comparison(
+    landscape: &[f64],
+    alternative: &[f64],
+    options: &StartOptions,
+) -> Result<()> {
+    ensure!(
+        landscape.len() == alternative.len(),
+        "both landscapes must have the same number of segments"
+    );
+    ensure!(
+        !options.adaptive,
+        "the adaptive mode can not be used when comparing"
+    );
+    Ok(())
+}
+
+/// The snapshots come from the clients, so their landscape and settings are validated like the ones of a new simulation
+fn validate_snapshot(limits: &Limits, snapshot: &Snapshot) -> Result<()> {
+    limits.validate_start(&snapshot.landscape, snapshot.hours)?;
+    ensure!(
+        snapshot.rain_rate >= 0.0 && snapshot.rain_rate.is_finite(),
+        "rain_rate must be finite and can not be negative"
+    );
+    let options = StartOptions {
+        schedule: snapshot.schedule.clone(),
+        loss_rate: snapshot.loss_rate,
+        delta_time: Some(snapshot.delta_time),
+        adaptive: snapshot.adaptive,
+        ..StartOptions::default()
+    };
+    options.validate()
+}
+
+/// The limits of the simulations that can be requested by a client, to protect the server from requests
+/// that would take too much memory or time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limits {
+    /// Maximum number of segments of a landscape
+    pub max_segments: usize,
+    /// Maximum simulated hours
+    pub max_hours: f64,
+    /// Range of the heights of the landscape, which are whole numbers that fit in the terrain of the simulation
+    pub min_level: f64,
+    pub max_level: f64,
+    /// Maximum number of simulations of a connection, including the default one
+    pub max_simulations: usize,
+    /// Maximum passes over the segments in a fast forward step, as the steps run on the executor of the connection
+    pub max_forward_work: f64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_segments: 1_000_000,
+            max_hours: 24.0 * 365.0,
+            min_level: 0.0,
+            max_level: u32::MAX as f64,
+            max_simulations: 16,
+            max_forward_work: 1e8,
+        }
+    }
+}
+
+impl Limits {
+    fn validate_landscape(&self, landscape: &[f64]) -> Result<()> {
+        ensure!(!landscape.is_empty(), "the landscape can not be empty");
+        ensure!(
+            landscape.len() <= self.max_segments,
+            "the landscape can not have more than {} segments",
+            self.max_segments
+        );
+        ensure!(
+            landscape
+                .iter()
+                .all(|level| (self.min_level..=self.max_level).contains(level)),
+            "the levels of the landscape must be in the range [{}, {}]",
+            self.min_level,
+            self.max_level
+        );
+        // The simulation keeps the heights as unsigned integers, even when the limits are wider
+        ensure!(
+            landscape
+                .iter()
+                .all(|level| level.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(level)),
+            "the levels of the landscape must be whole numbers in the range [0, {}]",
+            u32::MAX
+        );
+        Ok(())
+    }
+
+    /// NaN and infinite values are rejected as they are out of range
+    fn validate_hours(&self, hours: f64) -> Result<()> {
+        ensure!(
+            hours > 0.0 && hours <= self.max_hours,
+            "hours must be in the range (0, {}]",
+            self.max_hours
+        );
+        Ok(())
+    }
+
+    /// A fast forward step simulates `forward_hours / delta_time` steps, until an event in the adaptive mode.
+    /// The adaptive steps look at the lakes before and after the rain, and bisect the step of the event.
+    fn validate_forward(
+        &self,
+        segments: usize,
+        delta_time: f64,
+        forward_hours: f64,
+        adaptive: bool,
+    ) -> Result<()> {
+        let steps = (forward_hours / delta_time).ceil();
+        let passes = if adaptive {
+            let bisections = (delta_time / EVENT_TIME_TOLERANCE).log2().ceil().max(0.0);
+            steps * 3.0 + bisections * 2.0
+        } else {
+            steps
+        };
+        ensure!(
+            segments as f64 * passes <= self.max_forward_work,
+            "a fast forward step can not simulate more than {} segments, so forward_hours must be shorter or delta_time longer",
+            self.max_forward_work
+        );
+        Ok(())
+    }
+
+    fn validate_start(&self, landscape: &[f64], hours: f64) -> Result<()> {
+        self.validate_landscape(landscape)?;
+        self.validate_hours(hours)
+    }
+}
+
+/// The identifier chosen by the client for each of the simulations of a connection
+pub type SimulationId = String;
+
+/// An event addressed to (or coming from) a certain simulation of the connection.
+///
+/// The simulation can be omitted by the clients that only need one, which will use the default one.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub simulation: SimulationId,
+    /// An identifier chosen by the client to match the events with its requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// The generation of the scenario that scheduled a step, which is only used in the feedback loop
+    #[serde(skip)]
+    pub generation: Option<u64>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+impl Envelope {
+    pub fn new(simulation: SimulationId, event: Event) -> Self {
+        Self {
+            simulation,
+            id: None,
+            generation: None,
+            event,
+        }
+    }
+
+    /// Echo the id of the request that caused this event
+    pub fn with_id(mut self, id: Option<String>) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = Some(generation);
+        self
+    }
+}
+
+/// A simulation together with the settings of its playback.
+///
+/// When comparing scenarios, an alternative simulation is stepped in lock-step with the main one.
+struct Scenario {
     simulation: Simulation,
+    alternative: Option<Simulation>,
+    delta: Option<DeltaEncoder>,
+    /// The view is kept when the simulation is started again
+    view: Option<View>,
+    report_lakes: bool,
+    step_delay_millis: u64,
+    forward_hours: f64,
+    /// This changes every time that the steps are scheduled from scratch, to tell apart the stale ones
+    generation: u64,
+}
+
+impl Scenario {
+    fn new(simulation: Simulation) -> Self {
+        Self {
+            simulation,
+            alternative: None,
+            delta: None,
+            view: None,
+            report_lakes: false,
+            step_delay_millis: STEP_DELAY_MILLIS,
+            forward_hours: FORWARD_HOURS,
+            generation: 0,
+        }
+    }
+
+    /// Start the simulation, and the alternative one when comparing scenarios
+    fn start(
+        &mut self,
+        landscape: &[f64],
+        alternative: Option<&[f64]>,
+        hours: f64,
+        options: StartOptions,
+    ) {
+        self.alternative = alternative.map(|_| Simulation::new());
+        self.delta = options.delta_threshold.map(|threshold| {
+            DeltaEncoder::new(
+                threshold,
+                options.keyframe_interval.unwrap_or(KEYFRAME_INTERVAL),
+            )
+        });
+        self.report_lakes = options.lakes;
+        self.step_delay_millis = options.step_delay_millis.unwrap_or(STEP_DELAY_MILLIS);
+        self.forward_hours = options.forward_hours.unwrap_or(FORWARD_HOURS);
+        let delta_time = options.delta_time.unwrap_or(DELTA_TIME);
+        let schedule = options.schedule;
+        let loss_rate = options.loss_rate;
+        let adaptive = options.adaptive;
+        self.update(|simulation| {
+            simulation.set_schedule(schedule.clone());
+            simulation.set_loss_rate(loss_rate);
+            simulation.set_delta_time(delta_time);
+            simulation.set_adaptive(adaptive);
+        });
+        self.simulation.start(landscape, hours);
+        if let (Some(simulation), Some(landscape)) = (self.alternative.as_mut(), alternative) {
+            simulation.start(landscape, hours);
+        }
+        self.reschedule();
+    }
+
+    /// Start a new generation of steps, so the ones that were already scheduled are ignored
+    fn reschedule(&mut self) {
+        self.generation += 1;
+    }
+
+    /// A step of the current generation
+    fn timer(&self, id: SimulationId, event: Event) -> Envelope {
+        Envelope::new(id, event).with_generation(self.generation)
+    }
+
+    /// Apply the same change to the simulation and the alternative, to keep them in lock-step
+    fn update<F: FnMut(&mut Simulation)>(&mut self, mut f: F) {
+        f(&mut self.simulation);
+        if let Some(alternative) = self.alternative.as_mut() {
+            f(alternative);
+        }
+    }
+}
+
+/// This keeps the levels known by the client, to send only the segments that changed since they were sent.
+/// The changes are compared with the levels sent rather than with the previous step,
+/// so the small changes below the threshold don't accumulate into large errors.
+struct DeltaEncoder {
+    threshold: f64,
+    keyframe_interval: usize,
+    levels: Vec<f64>,
+    /// Number of deltas sent since the last keyframe
+    deltas: usize,
+}
+
+impl DeltaEncoder {
+    fn new(threshold: f64, keyframe_interval: usize) -> Self {
+        Self {
+            threshold,
+            keyframe_interval,
+            levels: Vec::new(),
+            deltas: 0,
+        }
+    }
+
+    /// Send a keyframe next, as the client may not know the last levels anymore
+    fn reset(&mut self) {
+        self.levels.clear();
+    }
+
+    /// The changed indices and values, or nothing when a keyframe needs to be sent instead
+    fn encode(&mut self, levels: &[f64]) -> Option<(Vec<usize>, Vec<f64>)> {
+        if self.levels.len() != levels.len() || self.deltas + 1 >= self.keyframe_interval {
+            self.levels = levels.to_vec();
+            self.deltas = 0;
+            return None;
+        }
+
+        let indices: Vec<usize> = (0..levels.len())
+            .filter(|index| (levels[*index] - self.levels[*index]).abs() > self.threshold)
+            .collect();
+        let values = indices.iter().map(|index| levels[*index]).collect();
+        for index in indices.iter() {
+            self.levels[*index] = levels[*index];
+        }
+        self.deltas += 1;
+        Some((indices, values))
+    }
+}
+
+pub struct Protocol {
+    scenarios: HashMap<SimulationId, Scenario>,
+    /// The latest generation of the closed scenarios, which is continued by the new ones
+    closed_generation: u64,
+    /// The sweep that is running, as a connection can only run one at a time
+    sweep: Option<SweepCancellation>,
+    limits: Limits,
+    /// The negotiated version of the protocol, if the client started with a hello
+    version: Option<u32>,
+    /// The capabilities that the client can use after a hello
+    capabilities: Vec<String>,
 }
 
 impl Protocol {
+    /// The simulation is used for the events without an explicit simulation
     pub fn new(simulation: Simulation) -> Self {
-        Self { simulation }
+        let mut scenarios = HashMap::new();
+        scenarios.insert(SimulationId::default(), Scenario::new(simulation));
+        Self {
+            scenarios,
+            closed_generation: 0,
+            sweep: None,
+            limits: Limits::default(),
+            version: None,
+            capabilities: Vec::new(),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
     }
 
     pub async fn run<
@@ -54,303 +727,2923 @@ impl Protocol {
         &mut self,
         outgoing_messages: MessagesOut,
         incoming_messages: MessagesIn,
-        mut outgoing_feedback_loop: FeedbackTx,
+        outgoing_feedback_loop: FeedbackTx,
         incoming_feedback_loop: FeedbackRx,
     ) -> Result<()>
     where
         MessagesOut: Sink<Message, Error = MessagesErr> + Unpin + Send + 'a,
         MessagesIn: Stream<Item = Result<Message, WsError>> + Unpin + Send + 'a,
         MessagesErr: Error + Send + Sync + 'static,
-        FeedbackTx: Sink<Event, Error = FeedbackErr> + Clone + Unpin + Send + 'static,
-        FeedbackRx: Stream<Item = Event> + Unpin + Send + 'a,
+        FeedbackTx: Sink<Envelope, Error = FeedbackErr> + Clone + Unpin + Send + 'static,
+        FeedbackRx: Stream<Item = Envelope> + Unpin + Send + 'a,
         FeedbackErr: Error + Send + Sync + 'static,
     {
-        let mut outgoing_events = outgoing_messages.with_flat_map(message_from_event);
+        // The events are written to the client concurrently, so a slow client doesn't slow down the simulations
+        let (outgoing_events, queued_events) = outbox(OUTBOX_CAPACITY, OUTBOX_MAX_SIZE);
+        // The events are filtered from the confirmation of a subscription on, before they wait for the client
+        let mut subscription = Subscription::default();
+        let mut outgoing_events = outgoing_events.with_flat_map(move |envelope: Envelope| {
+            let envelope = subscription.apply(envelope);
+            if let Some(Event::Subscribe {
+                subscription: confirmed,
+            }) = envelope.as_ref().map(|envelope| &envelope.event)
+            {
+                subscription = confirmed.clone();
+            }
+            stream::iter(envelope.map(Ok::<_, Infallible>))
+        });
+        let mut encoding = Encoding::Json;
+        let writer = queued_events
+            .map(Ok)
+            .forward(outgoing_messages.with_flat_map(move |envelope: Envelope| {
+                // The hello is answered with the original encoding, so the client knows when it changes
+                let next_encoding = if enables_message_pack(&envelope.event) {
+                    Encoding::MessagePack
+                } else {
+                    encoding
+                };
+                let message = message_from_envelope(envelope, encoding);
+                encoding = next_encoding;
+                message
+            }));
+        let reader = self.read(
+            incoming_messages,
+            &mut outgoing_events,
+            outgoing_feedback_loop,
+            incoming_feedback_loop,
+        );
+
+        future::try_join(reader, writer.map_err(anyhow::Error::from)).await?;
+        Ok(())
+    }
 
+    /// Process the incoming events until the client disconnects
+    async fn read<'a, MessagesIn, EventsOut, EventsErr, FeedbackTx, FeedbackRx, FeedbackErr>(
+        &mut self,
+        incoming_messages: MessagesIn,
+        outgoing_events: &mut EventsOut,
+        mut outgoing_feedback_loop: FeedbackTx,
+        incoming_feedback_loop: FeedbackRx,
+    ) -> Result<()>
+    where
+        MessagesIn: Stream<Item = Result<Message, WsError>> + Unpin + Send + 'a,
+        EventsOut: Sink<Envelope, Error = EventsErr> + Unpin,
+        EventsErr: Error + Send + Sync + 'static,
+        FeedbackTx: Sink<Envelope, Error = FeedbackErr> + Clone + Unpin + Send + 'static,
+        FeedbackRx: Stream<Item = Envelope> + Unpin + Send + 'a,
+        FeedbackErr: Error + Send + Sync + 'static,
+    {
         let incoming_events = incoming_messages
             .map_err(anyhow::Error::from)
-            .filter_map(event_from_try_message);
+            .filter_map(received_from_try_message);
 
         let mut multiplexed_events = stream::select_all(vec![
             incoming_events.boxed(),
-            incoming_feedback_loop.boxed(),
+            incoming_feedback_loop.map(Received::Feedback).boxed(),
         ]);
 
-        while let Some(event) = multiplexed_events.next().await {
-            log::info!("Recv: {:?}", event);
-            match event {
-                Event::Start { landscape, hours } => {
-                    self.simulation.start(landscape.as_slice(), hours);
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
+        while let Some(received) = multiplexed_events.next().await {
+            log::info!("Recv: {:?}", received);
+
+            let (envelope, origin) = match received {
+                Received::Request(envelope) => (envelope, Origin::Client),
+                Received::Feedback(envelope) => {
+                    let origin = Origin::Feedback(envelope.generation);
+                    (envelope, origin)
+                }
+                Received::Invalid {
+                    simulation,
+                    request_id,
+                    rejection,
+                } => {
+                    let error = rejection.into_envelope(simulation, request_id);
+                    send_event(error, &mut *outgoing_events).await?;
+                    continue;
+                }
+            };
+
+            let simulation = envelope.simulation.clone();
+            let request_id = envelope.id.clone();
+            let rejection = self
+                .process(
+                    envelope,
+                    origin,
+                    &mut *outgoing_events,
+                    &mut outgoing_feedback_loop,
+                )
+                .await?;
+            if let Some(rejection) = rejection {
+                let error = rejection.into_envelope(simulation, request_id);
+                send_event(error, &mut *outgoing_events).await?;
+            }
+        }
+
+        outgoing_events.close().await?;
+        Ok(())
+    }
+
+    /// Process an event and return the reason why it was rejected, if it was not valid
+    async fn process<EventsOut, EventsErr, FeedbackTx, FeedbackErr>(
+        &mut self,
+        envelope: Envelope,
+        origin: Origin,
+        outgoing_events: &mut EventsOut,
+        outgoing_feedback_loop: &mut FeedbackTx,
+    ) -> Result<Option<Rejection>>
+    where
+        EventsOut: Sink<Envelope, Error = EventsErr> + Unpin,
+        EventsErr: Error + Send + Sync + 'static,
+        FeedbackTx: Sink<Envelope, Error = FeedbackErr> + Clone + Unpin + Send + 'static,
+        FeedbackErr: Error + Send + Sync + 'static,
+    {
+        // The clients that started with a hello can only use the capabilities that were negotiated
+        if origin == Origin::Client && self.version.is_some() {
+            let missing = required_capabilities(&envelope)
+                .into_iter()
+                .find(|required| !self.capabilities.iter().any(|c| c == required));
+            if let Some(capability) = missing {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::UnknownEvent,
+                    format!("the {} capability was not negotiated", capability),
+                )));
+            }
+        }
+
+        let id = envelope.simulation;
+        let request_id = envelope.id;
+        let (landscape, alternative, hours, options) = match envelope.event {
+            Event::Start {
+                landscape,
+                hours,
+                options,
+            } => (landscape, None, hours, options),
+            Event::Compare {
+                landscape,
+                alternative,
+                hours,
+                options,
+            } => (landscape, Some(alternative), hours, options),
+            Event::Hello {
+                protocol_version,
+                capabilities,
+            } if origin == Origin::Client => {
+                return self
+                    .negotiate(
+                        id,
+                        request_id,
+                        protocol_version,
+                        capabilities,
+                        outgoing_events,
+                    )
+                    .await;
+            }
+            Event::Subscribe { subscription } if origin == Origin::Client => {
+                if let Err(err) = subscription.validate() {
+                    return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+                }
+                // The next progress is a keyframe, as the client may have missed the levels the deltas are based on
+                for scenario in self.scenarios.values_mut() {
+                    if let Some(delta) = scenario.delta.as_mut() {
+                        delta.reset();
+                    }
+                }
+                let confirmation = Event::Subscribe { subscription };
+                send_event(
+                    Envelope::new(id, confirmation).with_id(request_id),
+                    &mut *outgoing_events,
+                )
+                .await?;
+                return Ok(None);
+            }
+            Event::Close => {
+                let scenario = match self.scenarios.remove(&id) {
+                    Some(scenario) => scenario,
+                    None => {
+                        return Ok(Some(Rejection::new(
+                            ErrorCode::InvalidState,
+                            "the simulation does not exist",
+                        )))
+                    }
+                };
+                self.closed_generation = self.closed_generation.max(scenario.generation);
+                send_event(
+                    Envelope::new(id, Event::Close).with_id(request_id),
+                    &mut *outgoing_events,
+                )
+                .await?;
+                return Ok(None);
+            }
+            Event::Sweep { parameters } => {
+                let validation = self
+                    .limits
+                    .validate_start(&parameters.landscape, parameters.hours)
+                    .and_then(|_| {
+                        StartOptions {
+                            delta_time: parameters.delta_time,
+                            ..StartOptions::default()
+                        }
+                        .validate()
+                    })
+                    .and_then(|_| parameters.validate());
+                if let Err(err) = validation {
+                    return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+                }
+                if self.sweep.is_some() {
+                    return Ok(Some(Rejection::new(
+                        ErrorCode::InvalidState,
+                        "a connection can only run one sweep at a time",
+                    )));
+                }
+                let cancelled = Arc::new(AtomicBool::new(false));
+                start_sweep(
+                    id,
+                    request_id,
+                    parameters,
+                    cancelled.clone(),
+                    outgoing_feedback_loop.clone(),
+                );
+                self.sweep = Some(SweepCancellation(cancelled));
+                return Ok(None);
+            }
+            event @ Event::SweepProgress { .. } | event @ Event::SweepResult { .. }
+                if origin != Origin::Client =>
+            {
+                if let Event::SweepResult { .. } = event {
+                    self.sweep = None;
+                }
+                send_event(
+                    Envelope::new(id, event).with_id(request_id),
+                    &mut *outgoing_events,
+                )
+                .await?;
+                return Ok(None);
+            }
+            Event::Progress { .. }
+            | Event::ProgressDelta { .. }
+            | Event::ViewProgress { .. }
+            | Event::Lakes { .. }
+            | Event::Dropped { .. }
+            | Event::ComparisonProgress { .. }
+            | Event::SnapshotData { .. }
+            | Event::SweepProgress { .. }
+            | Event::SweepResult { .. }
+            | Event::Error { .. }
+            | Event::Hello { .. }
+            | Event::Subscribe { .. }
+            | Event::Step
+            | Event::ForwardStep
+            | Event::StepBack
+                if origin == Origin::Client =>
+            {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::UnknownEvent,
+                    "the event can only be sent by the server",
+                )));
+            }
+            event => {
+                return self
+                    .handle(
+                        id,
+                        request_id,
+                        event,
+                        origin,
+                        outgoing_events,
+                        outgoing_feedback_loop,
+                    )
+                    .await;
+            }
+        };
+
+        // The alternative of a comparison is simulated in the same steps
+        let segments = landscape.len() * if alternative.is_some() { 2 } else { 1 };
+        let validation = self
+            .limits
+            .validate_start(&landscape, hours)
+            .and_then(|_| options.validate())
+            .and_then(|_| {
+                self.limits.validate_forward(
+                    segments,
+                    options.delta_time.unwrap_or(DELTA_TIME),
+                    options.forward_hours.unwrap_or(FORWARD_HOURS),
+                    options.adaptive,
+                )
+            });
+        if let Err(err) = validation {
+            return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+        }
+        if let Some(alternative) = alternative.as_ref() {
+            let validation = self
+                .limits
+                .validate_landscape(alternative)
+                .and_then(|_| validate_comparison(&landscape, alternative, &options));
+            if let Err(err) = validation {
+                return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+            }
+        }
+        let scenario = match self.scenario_or_insert(&id) {
+            Ok(scenario) => scenario,
+            Err(rejection) => return Ok(Some(rejection)),
+        };
+        scenario.start(&landscape, alternative.as_deref(), hours, options);
+        send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+        tokio::spawn(send_event_delayed(
+            scenario.timer(id, Event::Step),
+            outgoing_feedback_loop.clone(),
+            scenario.step_delay_millis,
+        ));
+
+        Ok(None)
+    }
+
+    /// Get the scenario of a simulation, or add a new one when the connection has room for it.
+    /// The generations of the new scenarios continue after the closed ones, so the stale steps of those are still ignored.
+    fn scenario_or_insert(&mut self, id: &SimulationId) -> Result<&mut Scenario, Rejection> {
+        if !self.scenarios.contains_key(id) && self.scenarios.len() >= self.limits.max_simulations {
+            return Err(Rejection::new(
+                ErrorCode::InvalidState,
+                format!(
+                    "a connection can not have more than {} simulations",
+                    self.limits.max_simulations
+                ),
+            ));
+        }
+        let generation = self.closed_generation;
+        Ok(self.scenarios.entry(id.clone()).or_insert_with(|| {
+            let mut scenario = Scenario::new(Simulation::new());
+            scenario.generation = generation;
+            scenario
+        }))
+    }
+
+    /// Agree on the version of the protocol and the capabilities used for the rest of the connection
+    async fn negotiate<EventsOut, EventsErr>(
+        &mut self,
+        id: SimulationId,
+        request_id: Option<String>,
+        protocol_version: u32,
+        capabilities: Vec<String>,
+        outgoing_events: &mut EventsOut,
+    ) -> Result<Option<Rejection>>
+    where
+        EventsOut: Sink<Envelope, Error = EventsErr> + Unpin,
+        EventsErr: Error + Send + Sync + 'static,
+    {
+        if let Some(version) = self.version {
+            return Ok(Some(Rejection::new(
+                ErrorCode::InvalidState,
+                format!("the protocol version {} was already negotiated", version),
+            )));
+        }
+        if protocol_version < MIN_PROTOCOL_VERSION {
+            return Ok(Some(Rejection::new(
+                ErrorCode::InvalidParams,
+                format!(
+                    "the protocol version must be at least {}",
+                    MIN_PROTOCOL_VERSION
+                ),
+            )));
+        }
+
+        let version = protocol_version.min(PROTOCOL_VERSION);
+        self.version = Some(version);
+        log::info!("Negotiated protocol version {}", version);
+
+        let capabilities: Vec<String> = CAPABILITIES
+            .iter()
+            .filter(|capability| capabilities.iter().any(|c| c == *capability))
+            .map(|capability| capability.to_string())
+            .collect();
+        self.capabilities = capabilities.clone();
+        let hello = Event::Hello {
+            protocol_version: version,
+            capabilities,
+        };
+        send_event(
+            Envelope::new(id, hello).with_id(request_id),
+            &mut *outgoing_events,
+        )
+        .await?;
+
+        Ok(None)
+    }
+
+    /// Handle the events addressed to a simulation that was already started.
+    /// The events of the feedback loop that arrive when the state changed are silently ignored.
+    async fn handle<EventsOut, EventsErr, FeedbackTx, FeedbackErr>(
+        &mut self,
+        id: SimulationId,
+        request_id: Option<String>,
+        event: Event,
+        origin: Origin,
+        outgoing_events: &mut EventsOut,
+        outgoing_feedback_loop: &mut FeedbackTx,
+    ) -> Result<Option<Rejection>>
+    where
+        EventsOut: Sink<Envelope, Error = EventsErr> + Unpin,
+        EventsErr: Error + Send + Sync + 'static,
+        FeedbackTx: Sink<Envelope, Error = FeedbackErr> + Clone + Unpin + Send + 'static,
+        FeedbackErr: Error + Send + Sync + 'static,
+    {
+        // A snapshot can be restored into a new simulation
+        if let Event::Restore { snapshot } = &event {
+            let forward_hours = self
+                .scenarios
+                .get(&id)
+                .map_or(FORWARD_HOURS, |scenario| scenario.forward_hours);
+            let validation = validate_snapshot(&self.limits, snapshot).and_then(|_| {
+                self.limits.validate_forward(
+                    snapshot.landscape.len(),
+                    snapshot.delta_time,
+                    forward_hours,
+                    snapshot.adaptive,
+                )
+            });
+            if let Err(err) = validation {
+                return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+            }
+            if let Err(rejection) = self.scenario_or_insert(&id) {
+                return Ok(Some(rejection));
+            }
+        }
+
+        let limits = self.limits;
+        let scenario = match self.scenarios.get_mut(&id) {
+            Some(scenario)
+                if scenario.simulation.is_started() || matches!(event, Event::Restore { .. }) =>
+            {
+                scenario
+            }
+            _ if origin == Origin::Client => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidState,
+                    "the simulation has not been started",
+                )));
+            }
+            _ => return Ok(None),
+        };
+        // The steps scheduled before the last change of state are stale
+        if let Origin::Feedback(Some(generation)) = origin {
+            if generation != scenario.generation {
+                return Ok(None);
+            }
+        }
+        let simulation = &scenario.simulation;
+
+        match event {
+            Event::Step
+                if simulation.is_running()
+                    && !simulation.is_fast_forward()
+                    && !simulation.is_rewind() =>
+            {
+                scenario.update(Simulation::step);
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                if !scenario.simulation.is_finished() {
                     tokio::spawn(send_event_delayed(
-                        Event::Step,
+                        scenario.timer(id, Event::Step),
                         outgoing_feedback_loop.clone(),
-                        STEP_DELAY_MILLIS,
+                        scenario.step_delay_millis,
                     ));
                 }
-                Event::Step
-                    if self.simulation.is_running() && !self.simulation.is_fast_forward() =>
-                {
-                    self.simulation.step();
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
-                    if !self.simulation.is_finished() {
-                        tokio::spawn(send_event_delayed(
-                            Event::Step,
-                            outgoing_feedback_loop.clone(),
-                            STEP_DELAY_MILLIS,
-                        ));
-                    }
+            }
+            Event::Forward | Event::Resume if simulation.is_finished() => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidState,
+                    "the simulation is finished",
+                )));
+            }
+            Event::Forward => {
+                scenario.update(Simulation::start_forward);
+                scenario.reschedule();
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                send_event(
+                    scenario.timer(id, Event::ForwardStep),
+                    &mut *outgoing_feedback_loop,
+                )
+                .await?;
+            }
+            Event::ForwardStep if simulation.is_running() && simulation.is_fast_forward() => {
+                let hours = scenario.forward_hours;
+                scenario.update(|simulation| simulation.forward(hours));
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                if !scenario.simulation.is_finished() {
+                    send_event(
+                        scenario.timer(id, Event::ForwardStep),
+                        &mut *outgoing_feedback_loop,
+                    )
+                    .await?;
+                }
+            }
+            Event::Rewind if simulation.is_at_beginning() => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidState,
+                    "the simulation is at the beginning",
+                )));
+            }
+            Event::Rewind => {
+                scenario.update(Simulation::start_rewind);
+                scenario.reschedule();
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                tokio::spawn(send_event_delayed(
+                    scenario.timer(id, Event::StepBack),
+                    outgoing_feedback_loop.clone(),
+                    scenario.step_delay_millis,
+                ));
+            }
+            Event::StepBack if simulation.is_running() && simulation.is_rewind() => {
+                scenario.update(Simulation::step_back);
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                if !scenario.simulation.is_at_beginning() {
+                    tokio::spawn(send_event_delayed(
+                        scenario.timer(id, Event::StepBack),
+                        outgoing_feedback_loop.clone(),
+                        scenario.step_delay_millis,
+                    ));
                 }
-                Event::Forward => {
-                    self.simulation.start_forward();
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
-                    send_event(Event::ForwardStep, &mut outgoing_feedback_loop).await?;
+            }
+            Event::Pause => {
+                scenario.update(Simulation::pause);
+                scenario.reschedule();
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+            }
+            Event::Resume => {
+                scenario.update(Simulation::resume);
+                scenario.reschedule();
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                send_event(
+                    scenario.timer(id, Event::Step),
+                    &mut *outgoing_feedback_loop,
+                )
+                .await?;
+            }
+            Event::Seek { time } if time.is_nan() => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidParams,
+                    "time must be a number",
+                )));
+            }
+            Event::Seek { time } => {
+                scenario.update(|simulation| simulation.seek(time));
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+            }
+            Event::Extend { hours } if hours <= 0.0 || hours.is_nan() => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidParams,
+                    "hours must be positive",
+                )));
+            }
+            Event::Extend { hours } if simulation.get_hours() + hours > limits.max_hours => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidParams,
+                    format!(
+                        "the simulation can not last more than {} hours",
+                        limits.max_hours
+                    ),
+                )));
+            }
+            Event::Extend { hours } => {
+                let was_finished = simulation.is_finished();
+                scenario.update(|simulation| simulation.extend(hours));
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                if was_finished && scenario.simulation.is_running() {
+                    scenario.reschedule();
+                    send_event(
+                        scenario.timer(id, Event::Step),
+                        &mut *outgoing_feedback_loop,
+                    )
+                    .await?;
                 }
-                Event::ForwardStep
-                    if self.simulation.is_running() && self.simulation.is_fast_forward() =>
-                {
-                    self.simulation.forward(FORWARD_HOURS);
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
-                    if !self.simulation.is_finished() {
-                        send_event(Event::ForwardStep, &mut outgoing_feedback_loop).await?;
-                    }
+            }
+            Event::Snapshot if scenario.alternative.is_some() => {
+                return Ok(Some(Rejection::new(
+                    ErrorCode::InvalidState,
+                    "a comparison can not be saved, as the snapshots only hold the main simulation",
+                )));
+            }
+            Event::Snapshot => {
+                let snapshot = simulation.snapshot();
+                send_event(
+                    Envelope::new(id, Event::SnapshotData { snapshot }).with_id(request_id),
+                    &mut *outgoing_events,
+                )
+                .await?;
+            }
+            // Only the main simulation is restored, so the comparison is finished,
+            // and the speed, the view and the delta mode of the scenario are kept
+            Event::Restore { snapshot } => {
+                scenario.simulation = match Simulation::restore(snapshot) {
+                    Ok(simulation) => simulation,
+                    Err(err) => return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err))),
+                };
+                scenario.alternative = None;
+                scenario.reschedule();
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+                let simulation = &scenario.simulation;
+                if simulation.is_running() {
+                    let event = if simulation.is_fast_forward() {
+                        Event::ForwardStep
+                    } else if simulation.is_rewind() {
+                        Event::StepBack
+                    } else {
+                        Event::Step
+                    };
+                    send_event(scenario.timer(id, event), &mut *outgoing_feedback_loop).await?;
                 }
-                Event::Pause => {
-                    self.simulation.pause();
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
+            }
+            Event::SetView { view } => {
+                if let Err(err) = view.validate() {
+                    return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
+                }
+                scenario.view = Some(view);
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+            }
+            Event::ClearView => {
+                scenario.view = None;
+                if let Some(delta) = scenario.delta.as_mut() {
+                    delta.reset();
                 }
-                Event::Resume => {
-                    self.simulation.resume();
-                    send_progress(&self.simulation, &mut outgoing_events).await?;
-                    send_event(Event::Step, &mut outgoing_feedback_loop).await?;
+                send_progress(&id, &request_id, scenario, &mut *outgoing_events).await?;
+            }
+            Event::SetSpeed {
+                step_delay_millis,
+                forward_hours,
+            } => {
+                let segments =
+                    simulation.get_segments() * if scenario.alternative.is_some() { 2 } else { 1 };
+                let validation = validate_speed(step_delay_millis, forward_hours).and_then(|_| {
+                    limits.validate_forward(
+                        segments,
+                        simulation.get_delta_time(),
+                        forward_hours.unwrap_or(scenario.forward_hours),
+                        simulation.is_adaptive(),
+                    )
+                });
+                if let Err(err) = validation {
+                    return Ok(Some(Rejection::new(ErrorCode::InvalidParams, err)));
                 }
-                _ => (),
+                scenario.step_delay_millis =
+                    step_delay_millis.unwrap_or(scenario.step_delay_millis);
+                scenario.forward_hours = forward_hours.unwrap_or(scenario.forward_hours);
+                // The confirmation holds the speed in use, including the settings that were not changed
+                let confirmation = Event::SetSpeed {
+                    step_delay_millis: Some(scenario.step_delay_millis),
+                    forward_hours: Some(scenario.forward_hours),
+                };
+                send_event(
+                    Envelope::new(id, confirmation).with_id(request_id),
+                    &mut *outgoing_events,
+                )
+                .await?;
             }
+            _ => (),
         }
 
-        Ok(())
+        Ok(None)
     }
 }
 
-fn message_from_event<E>(event: Event) -> impl Stream<Item = Result<Message, E>>
-where
+/// Cancels the runs of a sweep when it is dropped, like when the connection is closed
+struct SweepCancellation(Arc<AtomicBool>);
+
+impl Drop for SweepCancellation {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The runs of a sweep are simulated in a different thread, as they can take a long time.
+/// The progress and the results are sent through the feedback loop, so they are sent in order with the rest of events.
+fn start_sweep<S, E>(
+    id: SimulationId,
+    request_id: Option<String>,
+    parameters: SweepParameters,
+    cancelled: Arc<AtomicBool>,
+    mut outbound: S,
+) where
+    S: Sink<Envelope, Error = E> + Unpin + Send + 'static,
     E: Error + Send + Sync + 'static,
 {
-    let maybe_message = serde_json::to_string(&event)
-        .map(Message::Text)
-        .map(Result::Ok)
-        .ok();
+    let (events_tx, mut events_rx) = mpsc::unbounded();
+
+    thread::spawn(move || {
+        let runs = run_sweep(&parameters, &cancelled, |completed, total| {
+            let _ = events_tx.unbounded_send(Event::SweepProgress { completed, total });
+        });
+        match runs {
+            Some(runs) => {
+                let _ = events_tx.unbounded_send(Event::SweepResult { runs });
+            }
+            None => log::info!("Sweep cancelled"),
+        }
+    });
+
+    tokio::spawn(async move {
+        while let Some(event) = events_rx.next().await {
+            let envelope = Envelope::new(id.clone(), event).with_id(request_id.clone());
+            send_event(envelope, &mut outbound).await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+}
+
+/// The events that can not be serialized are replaced by an error, so the client is always answered
+fn message_from_envelope<E>(
+    envelope: Envelope,
+    encoding: Encoding,
+) -> impl Stream<Item = Result<Message, E>>
+where
+    E: Error + Send + Sync + 'static,
+{
+    let message = encoding.encode(&envelope).or_else(|err| {
+        log::error!("Error serializing {:?}: {}", envelope, err);
+        let rejection = Rejection::new(ErrorCode::Internal, err);
+        encoding.encode(&rejection.into_envelope(envelope.simulation, envelope.id))
+    });
+
+    stream::iter(message.map(Result::Ok).ok().into_iter())
+}
+
+/// The capabilities needed by the requests of a client, the other ones are part of every version
+fn required_capabilities(envelope: &Envelope) -> Vec<&'static str> {
+    let mut capabilities = match &envelope.event {
+        Event::Start { options, .. } | Event::Compare { options, .. }
+            if options.delta_threshold.is_some() =>
+        {
+            vec!["delta"]
+        }
+        _ => vec![],
+    };
+    match &envelope.event {
+        Event::Compare { .. } => capabilities.push("comparison"),
+        Event::SetView { .. } | Event::ClearView => capabilities.push("view"),
+        Event::Sweep { .. } => capabilities.push("sweep"),
+        Event::Snapshot | Event::Restore { .. } => capabilities.push("snapshots"),
+        Event::Subscribe { .. } => capabilities.push("subscribe"),
+        _ => (),
+    }
+    if envelope.simulation != SimulationId::default() {
+        capabilities.push("simulations");
+    }
+    capabilities
+}
+
+/// The binary encoding is used after answering a hello with the msgpack capability
+fn enables_message_pack(event: &Event) -> bool {
+    match event {
+        Event::Hello { capabilities, .. } => capabilities
+            .iter()
+            .any(|capability| capability == "msgpack"),
+        _ => false,
+    }
+}
+
+async fn received_from_try_message(try_message: Result<Message>) -> Option<Received> {
+    try_message.ok().and_then(received_from_message)
+}
+
+/// The control messages of the WebSocket are ignored, but any other message that is not a valid event is rejected
+fn received_from_message(message: Message) -> Option<Received> {
+    let value = match decode(&message)? {
+        Ok(value) => value,
+        Err(err) => {
+            return Some(Received::Invalid {
+                simulation: SimulationId::default(),
+                request_id: None,
+                rejection: Rejection::new(ErrorCode::ParseError, err),
+            })
+        }
+    };
+
+    match Envelope::deserialize(&value) {
+        Ok(envelope) => Some(Received::Request(envelope)),
+        Err(err) => {
+            let code = if is_known_event(&value) {
+                ErrorCode::InvalidParams
+            } else {
+                ErrorCode::UnknownEvent
+            };
+            let field = |name: &str| value.get(name).and_then(Value::as_str).map(String::from);
+            Some(Received::Invalid {
+                simulation: field("simulation").unwrap_or_default(),
+                request_id: field("id"),
+                rejection: Rejection::new(code, err),
+            })
+        }
+    }
+}
+
+/// Check whether the name of an event is known, independently of its parameters
+fn is_known_event(value: &Value) -> bool {
+    match value.get("event").and_then(Value::as_str) {
+        Some(name) => EVENT_NAMES.contains(&name),
+        None => false,
+    }
+}
+
+/// The request id is echoed when the progress is caused by a request of the client.
+/// The reason of a step is only reported with the first progress after it, so the progress
+/// sent for other requests (like a pause) doesn't report the same event twice.
+///
+/// The view and the delta mode only apply to single simulations, as the progress of comparisons is always sent with all the levels.
+async fn send_progress<S, E>(
+    id: &str,
+    request_id: &Option<String>,
+    scenario: &mut Scenario,
+    mut outbound: S,
+) -> Result<()>
+where
+    S: Sink<Envelope, Error = E> + Unpin,
+    E: Error + Send + Sync + 'static,
+{
+    let reason = scenario.simulation.take_reason();
+    let simulation = &scenario.simulation;
+    let progress = match (scenario.alternative.as_ref(), scenario.view.as_ref()) {
+        (None, Some(view)) => {
+            let levels = simulation.get_levels();
+            let (start, end) = view.range(levels.len());
+            Event::ViewProgress {
+                running: simulation.is_running(),
+                time: simulation.get_time(),
+                start,
+                end,
+                buckets: view.buckets(&levels),
+                reason,
+            }
+        }
+        (None, None) => {
+            let levels = simulation.get_levels();
+            match scenario
+                .delta
+                .as_mut()
+                .and_then(|delta| delta.encode(&levels))
+            {
+                Some((indices, values)) => Event::ProgressDelta {
+                    running: simulation.is_running(),
+                    time: simulation.get_time(),
+                    indices,
+                    values,
+                    reason,
+                },
+                None => Event::Progress {
+                    running: simulation.is_running(),
+                    time: simulation.get_time(),
+                    levels,
+                    reason,
+                },
+            }
+        }
+        (Some(alternative), _) => {
+            let levels = simulation.get_levels();
+            let alternative_levels = alternative.get_levels();
+            let Comparison { deltas, statistics } =
+                Comparison::between(&levels, &alternative_levels);
+            Event::ComparisonProgress {
+                running: simulation.is_running(),
+                time: simulation.get_time(),
+                levels,
+                alternative_levels,
+                deltas,
+                statistics: Some(statistics),
+                reason,
+            }
+        }
+    };
+    let envelope = Envelope::new(id.to_string(), progress).with_id(request_id.clone());
+    send_event(envelope, &mut outbound).await?;
+
+    if scenario.report_lakes {
+        let lakes = Event::Lakes {
+            time: simulation.get_time(),
+            lakes: simulation.get_lakes(),
+        };
+        let envelope = Envelope::new(id.to_string(), lakes).with_id(request_id.clone());
+        send_event(envelope, &mut outbound).await?;
+    }
+
+    Ok(())
+}
+
+async fn send_event<S, E>(envelope: Envelope, mut outbound: S) -> Result<()>
+where
+    S: Sink<Envelope, Error = E> + Unpin,
+    E: Error + Send + Sync + 'static,
+{
+    log::info!("Send: {:?}", envelope);
+    outbound.send(envelope).await?;
+    Ok(())
+}
+
+async fn send_event_delayed<S, E>(envelope: Envelope, outbound: S, delay_millis: u64) -> Result<()>
+where
+    S: Sink<Envelope, Error = E> + Unpin,
+    E: Error + Send + Sync + 'static,
+{
+    sleep(Duration::from_millis(delay_millis)).await;
+    send_event(envelope, outbound).await
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_approx_eq::assert_approx_eq;
+    use futures::Future;
+    use futures_channel::mpsc::{self, Receiver, Sender};
+
+    use super::*;
+    use crate::simulation::{
+        tests::{assert_slice_approx_eq, assert_slice_approx_eq_with_epsilon},
+        Weather,
+    };
+    use crate::subscription::{EventType, ProgressField};
+
+    #[tokio::test]
+    async fn protocol_start() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                hours: 4.0,
+                landscape: vec![1.0, 2.0],
+                options: StartOptions::default(),
+            });
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS - 1)).await;
+
+            context.expect_feedback_empty();
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(running);
+                assert_approx_eq!(time, 0.0);
+                assert_slice_approx_eq(levels.as_slice(), &[1.0, 2.0])
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            })
+        })
+        .await
+
+        // feedback_loop_rx.map(Result::Ok).forward(feedback_loop_tx);
+    }
+
+    #[tokio::test]
+    async fn protocol_start_reporting_lakes() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                hours: 4.0,
+                landscape: vec![1.0, 2.0],
+                options: StartOptions {
+                    lakes: true,
+                    ..StartOptions::default()
+                },
+            });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, 0.0);
+            });
+
+            context.expect_lakes_with(|time, lakes| {
+                assert_approx_eq!(time, 0.0);
+                assert!(lakes.is_empty());
+            });
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, time, _| {
+                assert_approx_eq!(time, DELTA_TIME);
+            });
+
+            context.expect_lakes_with(|time, lakes| {
+                assert_approx_eq!(time, DELTA_TIME);
+                assert_eq!(lakes.len(), 1);
+                assert_eq!((lakes[0].start, lakes[0].end), (0, 0));
+                assert_approx_eq!(lakes[0].volume, 2.0 * DELTA_TIME);
+            });
+        })
+        .await
+    }
+
+    #[test]
+    fn protocol_start_options_are_optional() {
+        let message = Message::Text(
+            r#"{"event":"start","params":{"landscape":[1,2],"hours":2}}"#.to_string(),
+        );
+        assert_eq!(
+            envelope_from_message(message).map(|envelope| envelope.event),
+            Some(Event::Start {
+                landscape: vec![1.0, 2.0],
+                hours: 2.0,
+                options: StartOptions::default(),
+            })
+        );
+
+        let message = Message::Text(
+            r#"{"event":"start","params":{"landscape":[1],"hours":2,"loss_rate":0.5,
+                "schedule":[{"weather":"rain","hours":1},{"weather":"dry","hours":3}]}}"#
+                .to_string(),
+        );
+        assert_eq!(
+            envelope_from_message(message).map(|envelope| envelope.event),
+            Some(Event::Start {
+                landscape: vec![1.0],
+                hours: 2.0,
+                options: StartOptions {
+                    schedule: vec![
+                        Phase {
+                            weather: Weather::Rain,
+                            hours: 1.0,
+                        },
+                        Phase {
+                            weather: Weather::Dry,
+                            hours: 3.0,
+                        },
+                    ],
+                    loss_rate: 0.5,
+                    ..StartOptions::default()
+                },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn protocol_start_with_custom_speed() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                hours: 4.0,
+                landscape: vec![1.0, 2.0],
+                options: StartOptions {
+                    delta_time: Some(0.5),
+                    step_delay_millis: Some(20),
+                    ..StartOptions::default()
+                },
+            });
+
+            sleep(Duration::from_millis(60)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, 0.0);
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, time, _| {
+                assert_approx_eq!(time, 0.5);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_adaptive_steps_report_the_reason() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                hours: 4.0,
+                landscape: vec![2.0, 6.0, 5.0, 9.0],
+                options: StartOptions {
+                    adaptive: true,
+                    delta_time: Some(1.0),
+                    ..StartOptions::default()
+                },
+            });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with_reason(|time, reason| {
+                assert_approx_eq!(time, 0.0);
+                assert_eq!(reason, None);
+            });
+
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with_reason(|time, reason| {
+                assert_approx_eq!(time, 0.5, 0.01);
+                assert_eq!(reason, Some(StepReason::SinkFilled));
+            });
+
+            // The progress of a pause doesn't report the sink filled again
+            context.send_incoming_message(Event::Pause);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with_reason(|time, reason| {
+                assert_approx_eq!(time, 0.5, 0.01);
+                assert_eq!(reason, None);
+            });
+        })
+        .await
+    }
+
+    #[test]
+    fn protocol_start_options_with_invalid_loss_rate() {
+        for loss_rate in [-1.0, f64::INFINITY, f64::NAN] {
+            let options = StartOptions {
+                loss_rate,
+                ..StartOptions::default()
+            };
+            assert!(options.validate().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn protocol_start_with_invalid_options() {
+        with_context(Simulation::new(), |mut context| async move {
+            for options in [
+                StartOptions {
+                    delta_time: Some(-1.0),
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    delta_time: Some(MAX_DELTA_TIME + 1.0),
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    delta_time: Some(1e-12),
+                    forward_hours: Some(MAX_FORWARD_HOURS),
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    step_delay_millis: Some(0),
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    loss_rate: -1.0,
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    schedule: vec![Phase {
+                        weather: Weather::Dry,
+                        hours: 0.0,
+                    }],
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    schedule: vec![
+                        Phase {
+                            weather: Weather::Rain,
+                            hours: 1.0,
+                        };
+                        MAX_PHASES + 1
+                    ],
+                    ..StartOptions::default()
+                },
+                StartOptions {
+                    forward_hours: Some(0.0),
+                    ..StartOptions::default()
+                },
+            ] {
+                context.send_incoming_message(Event::Start {
+                    hours: 4.0,
+                    landscape: vec![1.0, 2.0],
+                    options,
+                });
+            }
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            for _ in 0..8 {
+                context.expect_error_with(|code, _| {
+                    assert_eq!(code, ErrorCode::InvalidParams);
+                });
+            }
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_set_speed() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_text(
+                r#"{"event":"setspeed","params":{"step_delay_millis":20},"id":"speed-1"}"#,
+            );
+            context.send_incoming_message(Event::SetSpeed {
+                step_delay_millis: None,
+                forward_hours: Some(2.0),
+            });
+            sleep(Duration::from_millis(10)).await;
+
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.id, Some("speed-1".to_string()));
+            assert_eq!(
+                envelope.event,
+                Event::SetSpeed {
+                    step_delay_millis: Some(20),
+                    forward_hours: Some(FORWARD_HOURS),
+                }
+            );
+            assert_eq!(
+                context.receive_message(),
+                Some(Event::SetSpeed {
+                    step_delay_millis: Some(20),
+                    forward_hours: Some(2.0),
+                })
+            );
+
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, _, _| {});
+            context.expect_feedback_empty();
+
+            sleep(Duration::from_millis(30)).await;
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+
+            context.send_incoming_message(Event::Forward);
+            sleep(Duration::from_millis(10)).await;
+            context.expect_progress_with(|_, _, _| {});
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::ForwardStep);
+            });
+
+            context.send_feedback(Event::ForwardStep);
+            sleep(Duration::from_millis(10)).await;
+            context.expect_progress_with(|_, time, _| {
+                assert_approx_eq!(time, 2.0 + DELTA_TIME, DELTA_TIME + 0.1);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_set_speed_with_invalid_values() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::SetSpeed {
+                step_delay_millis: Some(MAX_STEP_DELAY_MILLIS + 1),
+                forward_hours: None,
+            });
+            sleep(Duration::from_millis(10)).await;
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+            });
+            context.expect_progress_with(|_, _, _| {});
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_step() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], DELTA_TIME * 2.0);
+        with_context(simulation, |mut context| async move {
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS - 1)).await;
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(running);
+                assert_approx_eq!(time, DELTA_TIME);
+                assert_slice_approx_eq_with_epsilon(levels.as_slice(), &[1.2, 4.0], 0.01)
+            });
+
+            context.expect_feedback_empty();
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+
+            context.send_feedback(Event::Step);
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(!running);
+                assert_approx_eq!(time, DELTA_TIME * 2.0);
+                assert_slice_approx_eq_with_epsilon(levels.as_slice(), &[1.4, 4.0], 0.01)
+            });
+
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_forward() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], DELTA_TIME * 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Forward);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, 0.0);
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::ForwardStep);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_forward_step() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], FORWARD_HOURS * 2.0);
+        simulation.start_forward();
+        with_context(simulation, |mut context| async move {
+            context.send_feedback(Event::ForwardStep);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, FORWARD_HOURS, DELTA_TIME + 0.1);
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::ForwardStep);
+            });
+
+            context.send_feedback(Event::ForwardStep);
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(!running);
+                assert_approx_eq!(time, FORWARD_HOURS * 2.0, DELTA_TIME + 0.1);
+            });
+
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_seek() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 8.0], 4.0);
+        simulation.forward(3.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Seek { time: 1.0 });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(running);
+                assert_approx_eq!(time, 1.0);
+                assert_slice_approx_eq(levels.as_slice(), &[3.0, 8.0])
+            });
+
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_rewind() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        simulation.seek(1.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Rewind);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, 1.0);
+            });
+
+            context.expect_feedback_empty();
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::StepBack);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_step_back() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        simulation.step();
+        simulation.start_rewind();
+        with_context(simulation, |mut context| async move {
+            context.send_feedback(Event::Step);
+            context.send_feedback(Event::StepBack);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(!running);
+                assert_approx_eq!(time, 0.0);
+                assert_slice_approx_eq(levels.as_slice(), &[1.0, 4.0])
+            });
+
+            context.expect_message_empty();
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_extend_finished() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], DELTA_TIME);
+        simulation.step();
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Extend { hours: 1.0 });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, DELTA_TIME);
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_extend_running() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Extend { hours: 1.0 });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, _, _| {
+                assert!(running);
+            });
+
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_snapshot_and_restore() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 8.0], 4.0);
+        simulation.seek(1.0);
+        simulation.pause();
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Snapshot);
+
+            sleep(Duration::from_millis(10)).await;
+
+            let snapshot = match context.receive_message() {
+                Some(Event::SnapshotData { snapshot }) => snapshot,
+                other => panic!("Unexpected message: {:?}", other),
+            };
+
+            context.send_incoming_message(Event::Seek { time: 3.0 });
+            context.send_incoming_message(Event::Restore { snapshot });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, time, _| {
+                assert_approx_eq!(time, 3.0);
+            });
+
+            context.expect_progress_with(|running, time, levels| {
+                assert!(!running);
+                assert_approx_eq!(time, 1.0);
+                assert_slice_approx_eq(levels.as_slice(), &[3.0, 8.0])
+            });
+
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_snapshot_of_comparison() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Compare {
+                landscape: vec![1.0, 8.0],
+                alternative: vec![2.0, 8.0],
+                hours: 4.0,
+                options: StartOptions::default(),
+            });
+            context.send_incoming_message(Event::Snapshot);
+
+            sleep(Duration::from_millis(10)).await;
+
+            assert!(matches!(
+                context.receive_message(),
+                Some(Event::ComparisonProgress { .. })
+            ));
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
+            });
+            context.expect_message_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_restore_running_snapshot() {
+        let mut running = Simulation::new();
+        running.start(&[1.0, 8.0], 4.0);
+        let snapshot = running.snapshot();
+
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 8.0], 4.0);
+        simulation.pause();
+        with_context(simulation, |mut context| {
+            let snapshot = snapshot.clone();
+            async move {
+                context.send_incoming_message(Event::Restore { snapshot });
+
+                sleep(Duration::from_millis(10)).await;
+
+                context.expect_progress_with(|running, _, _| {
+                    assert!(running);
+                });
+
+                context.expect_feedback_with(|event| {
+                    assert_eq!(event, Event::Step);
+                });
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_restore_invalid_snapshot() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 8.0], 4.0);
+        simulation.pause();
+        let snapshot = simulation.snapshot();
+        with_context(simulation, |mut context| {
+            let snapshot = snapshot.clone();
+            async move {
+                let mut long = snapshot.clone();
+                long.hours = 1e12;
+                let mut without_steps = snapshot.clone();
+                without_steps.delta_time = 0.0;
+                without_steps.fast_forward = true;
+                let mut late = snapshot.clone();
+                late.time = 5.0;
+                let mut draining = snapshot.clone();
+                draining.loss_rate = -1.0;
+                let mut drying = snapshot;
+                drying.rain_rate = -1.0;
+                for snapshot in [long, without_steps, late, draining, drying] {
+                    context.send_incoming_message(Event::Restore { snapshot });
+                }
+
+                sleep(Duration::from_millis(10)).await;
+
+                for _ in 0..5 {
+                    context.expect_error_with(|code, _| {
+                        assert_eq!(code, ErrorCode::InvalidParams);
+                    });
+                }
+                context.expect_message_empty();
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn protocol_envelope_with_simulation() {
+        let message = Message::Text(r#"{"simulation":"a","event":"pause"}"#.to_string());
+        assert_eq!(
+            envelope_from_message(message),
+            Some(Envelope::new("a".to_string(), Event::Pause))
+        );
+
+        let json = serde_json::to_string(&Envelope::new(SimulationId::default(), Event::Pause));
+        assert_eq!(json.unwrap(), r#"{"event":"pause"}"#);
+    }
+
+    #[tokio::test]
+    async fn protocol_multiple_simulations() {
+        with_context(Simulation::new(), |mut context| async move {
+            for (id, landscape) in [("a", vec![1.0, 2.0]), ("b", vec![3.0, 1.0, 3.0])] {
+                context.send_incoming_envelope(
+                    id,
+                    Event::Start {
+                        hours: 4.0,
+                        landscape,
+                        options: StartOptions::default(),
+                    },
+                );
+            }
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            for (id, levels) in [("a", vec![1.0, 2.0]), ("b", vec![3.0, 1.0, 3.0])] {
+                let envelope = context.receive_envelope().unwrap();
+                assert_eq!(envelope.simulation, id);
+                assert!(matches!(&envelope.event,
+                    Event::Progress { levels: progress_levels, .. } if progress_levels == &levels));
+            }
+
+            let mut feedback: Vec<(SimulationId, Event)> = (0..2)
+                .map(|_| context.receive_feedback_envelope().unwrap())
+                .map(|envelope| (envelope.simulation, envelope.event))
+                .collect();
+            feedback.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                feedback,
+                vec![
+                    ("a".to_string(), Event::Step),
+                    ("b".to_string(), Event::Step)
+                ]
+            );
+
+            context.send_feedback_envelope(Envelope::new("b".to_string(), Event::Step));
+
+            sleep(Duration::from_millis(10)).await;
+
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "b");
+            assert!(matches!(envelope.event, Event::Progress { time, .. } if time > 0.0));
+
+            context.send_incoming_envelope("a", Event::Pause);
+            context.send_incoming_envelope("c", Event::Pause);
+
+            sleep(Duration::from_millis(10)).await;
+
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "a");
+            assert!(matches!(
+                envelope.event,
+                Event::Progress { running: false, .. }
+            ));
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "c");
+            assert!(matches!(
+                envelope.event,
+                Event::Error {
+                    code: ErrorCode::InvalidState,
+                    ..
+                }
+            ));
+            context.expect_message_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_compare() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Compare {
+                landscape: vec![1.0, 1.0, 1.0],
+                alternative: vec![1.0, 9.0, 1.0],
+                hours: 4.0,
+                options: StartOptions::default(),
+            });
+
+            sleep(Duration::from_millis(10)).await;
+
+            match context.receive_message() {
+                Some(Event::ComparisonProgress { deltas, .. }) => {
+                    assert_slice_approx_eq(deltas.as_slice(), &[0.0, 8.0, 0.0]);
+                }
+                other => panic!("Expected comparison progress, but found {:?}", other),
+            }
+
+            context.send_feedback(Event::Step);
+            context.send_incoming_message(Event::Seek { time: 1.0 });
+
+            sleep(Duration::from_millis(10)).await;
+
+            match context.receive_message() {
+                Some(Event::ComparisonProgress {
+                    running,
+                    time,
+                    levels,
+                    alternative_levels,
+                    statistics: Some(statistics),
+                    ..
+                }) => {
+                    assert!(running);
+                    assert_approx_eq!(time, DELTA_TIME);
+                    assert_slice_approx_eq(levels.as_slice(), &[1.1, 1.1, 1.1]);
+                    assert_slice_approx_eq(alternative_levels.as_slice(), &[1.15, 9.0, 1.15]);
+                    assert_eq!(statistics.changed, 3);
+                }
+                other => panic!("Expected comparison progress, but found {:?}", other),
+            }
+
+            match context.receive_message() {
+                Some(Event::ComparisonProgress {
+                    time,
+                    alternative_levels,
+                    ..
+                }) => {
+                    assert_approx_eq!(time, 1.0);
+                    assert_slice_approx_eq(alternative_levels.as_slice(), &[2.5, 9.0, 2.5]);
+                }
+                other => panic!("Expected comparison progress, but found {:?}", other),
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_compare_with_different_lengths() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Compare {
+                landscape: vec![1.0, 1.0],
+                alternative: vec![1.0],
+                hours: 4.0,
+                options: StartOptions::default(),
+            });
+
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+            });
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_sweep() {
+        with_context(Simulation::new(), |mut context| async move {
+            let parameters = SweepParameters {
+                landscape: vec![1.0, 3.0, 5.0],
+                hours: 1.0,
+                rain_rates: vec![1.0, 2.0],
+                durations: vec![1.0],
+                loss_rate: 0.0,
+                delta_time: None,
+            };
+            context.send_incoming_envelope(
+                "sweep",
+                Event::Sweep {
+                    parameters: parameters.clone(),
+                },
+            );
+
+            sleep(Duration::from_millis(500)).await;
+
+            for _ in 0..2 {
+                let envelope = context.receive_feedback_envelope().unwrap();
+                assert_eq!(envelope.simulation, "sweep");
+                assert!(matches!(
+                    envelope.event,
+                    Event::SweepProgress { total: 2, .. }
+                ));
+            }
+
+            let result = context.receive_feedback_envelope().unwrap();
+            match &result.event {
+                Event::SweepResult { runs } => {
+                    assert_eq!(runs.len(), 2);
+                    assert_slice_approx_eq(runs[0].final_levels.as_slice(), &[3.5, 3.5, 5.0]);
+                }
+                other => panic!("Expected sweep result, but found {:?}", other),
+            }
+
+            // The sweep is running until its result is sent
+            context.send_incoming_envelope(
+                "sweep",
+                Event::Sweep {
+                    parameters: parameters.clone(),
+                },
+            );
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
+            });
+
+            context.send_feedback_envelope(result);
+
+            sleep(Duration::from_millis(10)).await;
+
+            match context.receive_envelope() {
+                Some(Envelope {
+                    simulation,
+                    event: Event::SweepResult { runs },
+                    ..
+                }) => {
+                    assert_eq!(simulation, "sweep");
+                    assert_eq!(runs.len(), 2);
+                }
+                other => panic!("Expected sweep result, but found {:?}", other),
+            }
+
+            context.send_incoming_envelope("sweep", Event::Sweep { parameters });
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_message_empty();
+            assert!(matches!(
+                context
+                    .receive_feedback_envelope()
+                    .map(|envelope| envelope.event),
+                Some(Event::SweepProgress { .. })
+            ));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_invalid_sweep() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Sweep {
+                parameters: SweepParameters {
+                    landscape: vec![1.0],
+                    hours: 1.0,
+                    rain_rates: vec![-1.0],
+                    durations: vec![1.0],
+                    loss_rate: 0.0,
+                    delta_time: None,
+                },
+            });
+            context.send_incoming_message(Event::Sweep {
+                parameters: SweepParameters {
+                    landscape: vec![1.0],
+                    hours: 1.0,
+                    rain_rates: vec![1.0],
+                    durations: vec![1.0],
+                    loss_rate: 0.0,
+                    delta_time: Some(1e3),
+                },
+            });
+
+            sleep(Duration::from_millis(100)).await;
+
+            for _ in 0..2 {
+                context.expect_error_with(|code, _| {
+                    assert_eq!(code, ErrorCode::InvalidParams);
+                });
+            }
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_pause_and_resume() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 4.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Pause);
+
+            sleep(Duration::from_millis(500)).await;
+
+            context.expect_progress_with(|running, _, _| {
+                assert!(!running);
+            });
+
+            context.expect_feedback_empty();
+
+            context.send_incoming_message(Event::Resume);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, _, _| {
+                assert!(running);
+            });
+
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::Step);
+            });
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_error_for_invalid_json() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text("{\"event\":");
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, request_id| {
+                assert_eq!(code, ErrorCode::ParseError);
+                assert_eq!(request_id, None);
+            });
+            context.expect_message_empty();
+        })
+        .await
+    }
+
+    #[test]
+    fn protocol_event_names() {
+        let events = vec![
+            Event::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![],
+            },
+            Event::Start {
+                landscape: vec![],
+                hours: 0.0,
+                options: StartOptions::default(),
+            },
+            Event::Step,
+            Event::Progress {
+                running: false,
+                time: 0.0,
+                levels: vec![],
+                reason: None,
+            },
+            Event::ProgressDelta {
+                running: false,
+                time: 0.0,
+                indices: vec![],
+                values: vec![],
+                reason: None,
+            },
+            Event::ViewProgress {
+                running: false,
+                time: 0.0,
+                start: 0,
+                end: 0,
+                buckets: vec![],
+                reason: None,
+            },
+            Event::Lakes {
+                time: 0.0,
+                lakes: vec![],
+            },
+            Event::Dropped { frames: 0 },
+            Event::Compare {
+                landscape: vec![],
+                alternative: vec![],
+                hours: 0.0,
+                options: StartOptions::default(),
+            },
+            Event::ComparisonProgress {
+                running: false,
+                time: 0.0,
+                levels: vec![],
+                alternative_levels: vec![],
+                deltas: vec![],
+                statistics: None,
+                reason: None,
+            },
+            Event::Pause,
+            Event::Resume,
+            Event::Forward,
+            Event::ForwardStep,
+            Event::Rewind,
+            Event::StepBack,
+            Event::SetSpeed {
+                step_delay_millis: None,
+                forward_hours: None,
+            },
+            Event::Seek { time: 0.0 },
+            Event::SetView {
+                view: View {
+                    start: 0,
+                    end: 1,
+                    buckets: 1,
+                },
+            },
+            Event::ClearView,
+            Event::Close,
+            Event::Extend { hours: 0.0 },
+            Event::Sweep {
+                parameters: SweepParameters {
+                    landscape: vec![],
+                    hours: 0.0,
+                    rain_rates: vec![],
+                    durations: vec![],
+                    loss_rate: 0.0,
+                    delta_time: None,
+                },
+            },
+            Event::SweepProgress {
+                completed: 0,
+                total: 0,
+            },
+            Event::SweepResult { runs: vec![] },
+            Event::Snapshot,
+            Event::SnapshotData {
+                snapshot: Simulation::new().snapshot(),
+            },
+            Event::Restore {
+                snapshot: Simulation::new().snapshot(),
+            },
+            Event::Subscribe {
+                subscription: Subscription::default(),
+            },
+            Event::Error {
+                code: ErrorCode::Internal,
+                message: String::new(),
+                request_id: None,
+            },
+        ];
+        // A new variant doesn't compile until it is added to the events above
+        for event in &events {
+            match event {
+                Event::Hello { .. }
+                | Event::Start { .. }
+                | Event::Step
+                | Event::Progress { .. }
+                | Event::ProgressDelta { .. }
+                | Event::ViewProgress { .. }
+                | Event::Lakes { .. }
+                | Event::Dropped { .. }
+                | Event::Compare { .. }
+                | Event::ComparisonProgress { .. }
+                | Event::Pause
+                | Event::Resume
+                | Event::Forward
+                | Event::ForwardStep
+                | Event::Rewind
+                | Event::StepBack
+                | Event::SetSpeed { .. }
+                | Event::Seek { .. }
+                | Event::SetView { .. }
+                | Event::ClearView
+                | Event::Close
+                | Event::Extend { .. }
+                | Event::Sweep { .. }
+                | Event::SweepProgress { .. }
+                | Event::SweepResult { .. }
+                | Event::Snapshot
+                | Event::SnapshotData { .. }
+                | Event::Restore { .. }
+                | Event::Subscribe { .. }
+                | Event::Error { .. } => (),
+            }
+        }
+
+        let names: Vec<String> = events
+            .iter()
+            .map(|event| {
+                let value = serde_json::to_value(event).unwrap();
+                assert!(is_known_event(&value), "Unknown event: {}", value);
+                value["event"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(names, EVENT_NAMES);
+        assert!(!is_known_event(&serde_json::json!({ "event": "jump" })));
+        assert!(!is_known_event(&serde_json::json!({ "params": {} })));
+    }
+
+    #[tokio::test]
+    async fn protocol_steps_from_the_client() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 2.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Step);
+            context.send_incoming_message(Event::ForwardStep);
+            context.send_incoming_message(Event::StepBack);
+
+            sleep(Duration::from_millis(10)).await;
+
+            for _ in 0..3 {
+                context.expect_error_with(|code, _| {
+                    assert_eq!(code, ErrorCode::UnknownEvent);
+                });
+            }
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_error_for_unknown_event() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text(r#"{"event":"jump","id":"1"}"#);
+            context.send_incoming_text(
+                r#"{"event":"progress","params":{"running":true,"time":0,"levels":[]}}"#,
+            );
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, request_id| {
+                assert_eq!(code, ErrorCode::UnknownEvent);
+                assert_eq!(request_id, Some("1".to_string()));
+            });
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::UnknownEvent);
+            });
+            context.expect_message_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_error_for_invalid_params() {
+        with_context(Simulation::new(), |mut context| async move {
+            context
+                .send_incoming_text(r#"{"event":"start","params":{"landscape":"flat"},"id":"2"}"#);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, request_id| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+                assert_eq!(request_id, Some("2".to_string()));
+            });
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_error_for_pause_before_start() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text(r#"{"event":"pause","id":"3"}"#);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, request_id| {
+                assert_eq!(code, ErrorCode::InvalidState);
+                assert_eq!(request_id, Some("3".to_string()));
+            });
+            context.expect_message_empty();
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_error_for_resume_when_finished() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 2.0], DELTA_TIME);
+        simulation.step();
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Resume);
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
+            });
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_request_id_is_echoed() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text(
+                r#"{"id":"start-1","event":"start","params":{"landscape":[1,2],"hours":4,"lakes":true}}"#,
+            );
+
+            sleep(Duration::from_millis(10)).await;
+
+            for _ in 0..2 {
+                let envelope = context.receive_envelope().unwrap();
+                assert_eq!(envelope.id, Some("start-1".to_string()));
+            }
+
+            context.send_feedback(Event::Step);
+            context.send_incoming_text(r#"{"id":"pause-1","event":"pause"}"#);
+
+            sleep(Duration::from_millis(10)).await;
+
+            for _ in 0..2 {
+                assert_eq!(context.receive_envelope().unwrap().id, None);
+            }
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.id, Some("pause-1".to_string()));
+            assert!(matches!(
+                envelope.event,
+                Event::Progress { running: false, .. }
+            ));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_progress_delta() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                landscape: vec![1.0, 5.0],
+                hours: 4.0,
+                options: StartOptions {
+                    delta_threshold: Some(0.0),
+                    keyframe_interval: Some(3),
+                    ..StartOptions::default()
+                },
+            });
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, _, levels| {
+                assert_slice_approx_eq(levels.as_slice(), &[1.0, 5.0]);
+            });
+
+            for _ in 0..2 {
+                context.send_feedback(Event::Step);
+                sleep(Duration::from_millis(10)).await;
+                match context.receive_message() {
+                    Some(Event::ProgressDelta { indices, .. }) => assert_eq!(indices, vec![0]),
+                    other => panic!("Expected progress delta, but found {:?}", other),
+                }
+            }
+
+            context.send_feedback(Event::Step);
+            sleep(Duration::from_millis(10)).await;
+            context.expect_progress_with(|_, time, levels| {
+                assert_approx_eq!(time, 3.0 * DELTA_TIME);
+                assert_eq!(levels.len(), 2);
+            });
+        })
+        .await
+    }
+
+    #[test]
+    fn delta_encoder_with_threshold() {
+        let mut delta = DeltaEncoder::new(0.5, 10);
+
+        assert_eq!(delta.encode(&[1.0, 2.0, 3.0]), None);
+        assert_eq!(delta.encode(&[1.4, 2.0, 4.0]), Some((vec![2], vec![4.0])));
+        // The small changes are compared with the levels that were sent
+        assert_eq!(delta.encode(&[1.8, 2.0, 4.0]), Some((vec![0], vec![1.8])));
+        assert_eq!(delta.encode(&[1.0, 2.0]), None);
+    }
+
+    #[tokio::test]
+    async fn protocol_set_view() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 3.0, 2.0, 4.0, 6.0, 5.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::SetView {
+                view: View {
+                    start: 0,
+                    end: 4,
+                    buckets: 2,
+                },
+            });
+            context.send_incoming_message(Event::SetView {
+                view: View {
+                    start: 2,
+                    end: 2,
+                    buckets: 2,
+                },
+            });
+            context.send_incoming_message(Event::ClearView);
+
+            sleep(Duration::from_millis(10)).await;
+
+            match context.receive_message() {
+                Some(Event::ViewProgress {
+                    start,
+                    end,
+                    buckets,
+                    ..
+                }) => {
+                    assert_eq!((start, end), (0, 4));
+                    assert_eq!(buckets.len(), 2);
+                    assert_approx_eq!(buckets[0].max, 3.0);
+                    assert_approx_eq!(buckets[1].min, 2.0);
+                }
+                other => panic!("Expected view progress, but found {:?}", other),
+            }
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+            });
+            context.expect_progress_with(|_, _, levels| {
+                assert_eq!(levels.len(), 6);
+            });
+            context.expect_feedback_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_merges_progress_for_slow_clients() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 2.0], 300.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Forward);
+
+            // The client doesn't receive any message until the simulation is finished
+            let mut forward_steps = 0;
+            for _ in 0..10_000 {
+                tokio::task::yield_now().await;
+                if let Some(envelope) = context.receive_feedback_envelope() {
+                    context.send_feedback_envelope(envelope);
+                    forward_steps += 1;
+                }
+            }
+
+            sleep(Duration::from_millis(10)).await;
+
+            let mut events = vec![];
+            while let Some(event) = context.receive_message() {
+                events.push(event);
+                tokio::task::yield_now().await;
+            }
+            let dropped: usize = events
+                .iter()
+                .map(|event| match event {
+                    Event::Dropped { frames } => *frames,
+                    _ => 0,
+                })
+                .sum();
+            let progress = events
+                .iter()
+                .filter(|event| matches!(event, Event::Progress { .. }))
+                .count();
+            assert!(events.len() <= 3 * OUTBOX_CAPACITY);
+            assert!(forward_steps > 2 * OUTBOX_CAPACITY);
+            assert_eq!(progress + dropped, forward_steps + 1);
+            assert!(matches!(
+                events.last(),
+                Some(Event::Progress { running: false, time, .. }) if *time == 300.0
+            ));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_pause_and_resume_quickly() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 2.0], 4.0);
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Resume);
+            context.send_incoming_message(Event::Pause);
+            context.send_incoming_message(Event::Resume);
+            context.send_incoming_message(Event::Resume);
+
+            sleep(Duration::from_millis(10)).await;
+
+            for _ in 0..4 {
+                context.expect_progress_with(|_, time, _| {
+                    assert_approx_eq!(time, 0.0);
+                });
+            }
+
+            // Only the steps scheduled by the last resume are simulated
+            let steps: Vec<Envelope> = (0..3)
+                .map(|_| context.receive_feedback_envelope().unwrap())
+                .collect();
+            context.expect_feedback_empty();
+            for step in steps {
+                context.send_feedback_envelope(step);
+            }
+
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|running, time, _| {
+                assert!(running);
+                assert_approx_eq!(time, DELTA_TIME);
+            });
+            context.expect_message_empty();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn protocol_start_again_drops_the_steps_of_the_previous_run() {
+        with_context(Simulation::new(), |mut context| async move {
+            for landscape in [vec![1.0, 2.0], vec![5.0]] {
+                context.send_incoming_message(Event::Start {
+                    landscape,
+                    hours: 4.0,
+                    options: StartOptions {
+                        step_delay_millis: Some(MIN_STEP_DELAY_MILLIS),
+                        ..StartOptions::default()
+                    },
+                });
+            }
+
+            sleep(Duration::from_millis(MIN_STEP_DELAY_MILLIS + 50)).await;
 
-    stream::iter(maybe_message.into_iter())
-}
+            context.expect_progress_with(|_, _, levels| assert_eq!(levels.len(), 2));
+            context.expect_progress_with(|_, _, levels| assert_eq!(levels.len(), 1));
+            for _ in 0..2 {
+                let step = context.receive_feedback_envelope().unwrap();
+                context.send_feedback_envelope(step);
+            }
 
-async fn event_from_try_message(try_message: Result<Message>) -> Option<Event> {
-    try_message.ok().and_then(event_from_message)
-}
+            sleep(Duration::from_millis(10)).await;
 
-fn event_from_message(message: Message) -> Option<Event> {
-    message
-        .to_text()
-        .ok()
-        .and_then(|text| serde_json::from_str::<Event>(text).ok())
-}
+            context.expect_progress_with(|_, time, levels| {
+                assert_approx_eq!(time, DELTA_TIME);
+                assert_eq!(levels.len(), 1);
+            });
+            context.expect_message_empty();
+        })
+        .await
+    }
 
-async fn send_progress<S, E>(simulation: &Simulation, outbound: S) -> Result<()>
-where
-    S: Sink<Event, Error = E> + Unpin,
-    E: Error + Send + Sync + 'static,
-{
-    let progress = Event::Progress {
-        running: simulation.is_running(),
-        time: simulation.get_time(),
-        levels: simulation.get_levels(),
-    };
-    send_event(progress, outbound).await
-}
+    #[tokio::test]
+    async fn protocol_forward_and_rewind_quickly() {
+        let mut simulation = Simulation::new();
+        simulation.start(&[1.0, 2.0], 4.0);
+        simulation.step();
+        with_context(simulation, |mut context| async move {
+            context.send_incoming_message(Event::Forward);
+            context.send_incoming_message(Event::Rewind);
+            context.send_incoming_message(Event::Forward);
 
-async fn send_event<S, E>(event: Event, mut outbound: S) -> Result<()>
-where
-    S: Sink<Event, Error = E> + Unpin,
-    E: Error + Send + Sync + 'static,
-{
-    log::info!("Send: {:?}", event);
-    outbound.send(event).await?;
-    Ok(())
-}
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 50)).await;
 
-async fn send_event_delayed<S, E>(event: Event, outbound: S, delay_millis: u64) -> Result<()>
-where
-    S: Sink<Event, Error = E> + Unpin,
-    E: Error + Send + Sync + 'static,
-{
-    sleep(Duration::from_millis(delay_millis)).await;
-    send_event(event, outbound).await
-}
+            for _ in 0..3 {
+                context.expect_progress_with(|_, _, _| {});
+            }
+            let steps: Vec<Envelope> = (0..3)
+                .map(|_| context.receive_feedback_envelope().unwrap())
+                .collect();
+            for step in steps {
+                context.send_feedback_envelope(step);
+            }
 
-#[cfg(test)]
-mod tests {
-    use assert_approx_eq::assert_approx_eq;
-    use futures::Future;
-    use futures_channel::mpsc::{self, Receiver, Sender};
+            sleep(Duration::from_millis(10)).await;
 
-    use super::*;
-    use crate::simulation::{
-        tests::{assert_slice_approx_eq, assert_slice_approx_eq_with_epsilon},
-        DELTA_TIME,
-    };
+            // Only the last forward step goes on
+            context.expect_progress_with(|_, time, _| {
+                assert_approx_eq!(time, DELTA_TIME + FORWARD_HOURS, 0.2);
+            });
+            context.expect_message_empty();
+            context.expect_feedback_with(|event| {
+                assert_eq!(event, Event::ForwardStep);
+            });
+        })
+        .await
+    }
 
     #[tokio::test]
-    async fn protocol_start() {
+    async fn protocol_hello() {
         with_context(Simulation::new(), |mut context| async move {
-            context.send_incoming_message(Event::Start {
-                hours: 4.0,
-                landscape: vec![1.0, 2.0],
+            context.send_incoming_message(Event::Hello {
+                protocol_version: PROTOCOL_VERSION + 1,
+                capabilities: vec!["sweep".to_string(), "teleport".to_string()],
             });
 
-            sleep(Duration::from_millis(STEP_DELAY_MILLIS - 1)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_feedback_empty();
+            assert_eq!(
+                context.receive_message(),
+                Some(Event::Hello {
+                    protocol_version: PROTOCOL_VERSION,
+                    capabilities: vec!["sweep".to_string()],
+                })
+            );
+
+            context.send_incoming_message(Event::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec![],
+            });
 
-            sleep(Duration::from_millis(500)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, time, levels| {
-                assert!(running);
-                assert_approx_eq!(time, 0.0);
-                assert_slice_approx_eq(levels.as_slice(), &[1.0, 2.0])
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
             });
-
-            context.expect_feedback_with(|event| {
-                assert_eq!(event, Event::Step);
-            })
         })
         .await
-
-        // feedback_loop_rx.map(Result::Ok).forward(feedback_loop_tx);
     }
 
     #[tokio::test]
-    async fn protocol_step() {
-        let mut simulation = Simulation::new();
-        simulation.start(&[1.0, 4.0], DELTA_TIME * 2.0);
-        with_context(simulation, |mut context| async move {
-            context.send_feedback(Event::Step);
+    async fn protocol_hello_gates_the_capabilities() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec!["simulations".to_string()],
+            });
+            context.send_incoming_message(Event::Snapshot);
+            context.send_incoming_message(Event::ClearView);
+            context.send_incoming_envelope(
+                "a",
+                Event::Start {
+                    landscape: vec![1.0, 2.0],
+                    hours: 4.0,
+                    options: StartOptions {
+                        delta_threshold: Some(0.1),
+                        ..StartOptions::default()
+                    },
+                },
+            );
+            context.send_incoming_envelope(
+                "a",
+                Event::Start {
+                    landscape: vec![1.0, 2.0],
+                    hours: 4.0,
+                    options: StartOptions::default(),
+                },
+            );
 
-            sleep(Duration::from_millis(STEP_DELAY_MILLIS - 1)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, time, levels| {
-                assert!(running);
-                assert_approx_eq!(time, DELTA_TIME);
-                assert_slice_approx_eq_with_epsilon(levels.as_slice(), &[1.2, 4.0], 0.01)
-            });
+            assert!(matches!(
+                context.receive_message(),
+                Some(Event::Hello { .. })
+            ));
+            for _ in 0..3 {
+                context.expect_error_with(|code, _| {
+                    assert_eq!(code, ErrorCode::UnknownEvent);
+                });
+            }
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "a");
+            assert!(matches!(envelope.event, Event::Progress { .. }));
+            context.expect_message_empty();
+        })
+        .await
+    }
 
-            context.expect_feedback_empty();
+    #[tokio::test]
+    async fn protocol_hello_with_message_pack() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                capabilities: vec!["msgpack".to_string()],
+            });
+            let start = Envelope::new(
+                SimulationId::default(),
+                Event::Start {
+                    landscape: vec![1.0, 2.0],
+                    hours: 4.0,
+                    options: StartOptions::default(),
+                },
+            );
+            let message = Encoding::MessagePack.encode(&start).unwrap();
+            context.message_tx.try_send(Ok(message)).unwrap();
+            context.send_incoming_message_binary(vec![0xc1]);
 
-            sleep(Duration::from_millis(500)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_feedback_with(|event| {
-                assert_eq!(event, Event::Step);
+            let message = context.receive_raw_message().unwrap();
+            assert!(message.is_text());
+            assert!(matches!(
+                envelope_from_message(message).map(|envelope| envelope.event),
+                Some(Event::Hello { .. })
+            ));
+
+            let message = context.receive_raw_message().unwrap();
+            assert!(message.is_binary());
+            assert!(matches!(
+                envelope_from_message(message).map(|envelope| envelope.event),
+                Some(Event::Progress { levels, .. }) if levels == vec![1.0, 2.0]
+            ));
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::ParseError);
             });
+        })
+        .await
+    }
 
-            context.send_feedback(Event::Step);
+    #[tokio::test]
+    async fn protocol_hello_with_unsupported_version() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text(r#"{"event":"hello","params":{"protocol_version":0}}"#);
 
-            sleep(Duration::from_millis(500)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, time, levels| {
-                assert!(!running);
-                assert_approx_eq!(time, DELTA_TIME * 2.0);
-                assert_slice_approx_eq_with_epsilon(levels.as_slice(), &[1.4, 4.0], 0.01)
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
             });
-
-            context.expect_feedback_empty();
+            context.expect_message_empty();
         })
         .await
     }
 
     #[tokio::test]
-    async fn protocol_forward() {
-        let mut simulation = Simulation::new();
-        simulation.start(&[1.0, 4.0], DELTA_TIME * 4.0);
-        with_context(simulation, |mut context| async move {
-            context.send_incoming_message(Event::Forward);
+    async fn protocol_subscribe() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_text(
+                r#"{"event":"subscribe","params":{"events":["progress"],"fields":["reason"]}}"#,
+            );
+            context.send_incoming_message(Event::Start {
+                landscape: vec![1.0, 2.0],
+                hours: 4.0,
+                options: StartOptions {
+                    lakes: true,
+                    ..StartOptions::default()
+                },
+            });
+            context.send_incoming_text(r#"{"event":"subscribe","params":{"fields":["flux"]}}"#);
 
             sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, time, _| {
+            assert_eq!(
+                context.receive_message(),
+                Some(Event::Subscribe {
+                    subscription: Subscription {
+                        events: Some(vec![EventType::Progress]),
+                        fields: Some(vec![ProgressField::Reason]),
+                    },
+                })
+            );
+            context.expect_progress_with(|running, _, levels| {
                 assert!(running);
-                assert_approx_eq!(time, 0.0);
+                assert!(levels.is_empty());
             });
-
-            context.expect_feedback_with(|event| {
-                assert_eq!(event, Event::ForwardStep);
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
             });
+            context.expect_message_empty();
         })
         .await
     }
 
     #[tokio::test]
-    async fn protocol_forward_step() {
-        let mut simulation = Simulation::new();
-        simulation.start(&[1.0, 4.0], FORWARD_HOURS * 2.0);
-        simulation.start_forward();
-        with_context(simulation, |mut context| async move {
-            context.send_feedback(Event::ForwardStep);
+    async fn protocol_subscribe_sends_a_keyframe() {
+        with_context(Simulation::new(), |mut context| async move {
+            context.send_incoming_message(Event::Start {
+                landscape: vec![1.0, 5.0],
+                hours: 4.0,
+                options: StartOptions {
+                    delta_threshold: Some(0.0),
+                    ..StartOptions::default()
+                },
+            });
+            context.send_incoming_text(
+                r#"{"event":"subscribe","params":{"events":["progressdelta"]}}"#,
+            );
+            context.send_incoming_text(
+                r#"{"event":"subscribe","params":{"events":["progress","progressdelta"]}}"#,
+            );
 
             sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, time, _| {
-                assert!(running);
-                assert_approx_eq!(time, FORWARD_HOURS, DELTA_TIME + 0.1);
+            context.expect_progress_with(|_, _, levels| {
+                assert_eq!(levels.len(), 2);
             });
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+            });
+            assert!(matches!(
+                context.receive_message(),
+                Some(Event::Subscribe { .. })
+            ));
 
-            context.expect_feedback_with(|event| {
-                assert_eq!(event, Event::ForwardStep);
+            context.send_feedback(Event::Step);
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_progress_with(|_, time, levels| {
+                assert_approx_eq!(time, DELTA_TIME);
+                assert_eq!(levels.len(), 2);
             });
+        })
+        .await
+    }
 
-            context.send_feedback(Event::ForwardStep);
+    #[test]
+    fn protocol_error_serialization() {
+        let error = Rejection::new(ErrorCode::InvalidState, "not started")
+            .into_envelope(SimulationId::default(), Some("4".to_string()));
 
-            sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"id":"4","event":"error","params":{"code":"invalid_state","message":"not started","request_id":"4"}}"#
+        );
+    }
 
-            context.expect_progress_with(|running, time, _| {
-                assert!(!running);
-                assert_approx_eq!(time, FORWARD_HOURS * 2.0, DELTA_TIME + 0.1);
-            });
+    #[tokio::test]
+    async fn protocol_start_beyond_limits() {
+        let limits = Limits {
+            max_segments: 2,
+            max_hours: 10.0,
+            min_level: 0.0,
+            max_level: 100.0,
+            max_simulations: 1,
+            max_forward_work: 1e8,
+        };
+        let protocol = Protocol::new(Simulation::new()).with_limits(limits);
+        with_protocol_context(protocol, |mut context| async move {
+            for (landscape, hours) in [
+                (vec![], 1.0),
+                (vec![1.0, 2.0, 3.0], 1.0),
+                (vec![1.0, -1.0], 1.0),
+                (vec![1.0, 101.0], 1.0),
+                (vec![1.0, 1.5], 1.0),
+                (vec![1.0, 2.0], 11.0),
+                (vec![1.0, 2.0], -1.0),
+            ] {
+                context.send_incoming_message(Event::Start {
+                    landscape,
+                    hours,
+                    options: StartOptions::default(),
+                });
+            }
+            context.send_incoming_text(
+                r#"{"event":"start","params":{"landscape":[1,2],"hours":null}}"#,
+            );
 
+            sleep(Duration::from_millis(STEP_DELAY_MILLIS + 100)).await;
+
+            for _ in 0..8 {
+                context.expect_error_with(|code, _| {
+                    assert_eq!(code, ErrorCode::InvalidParams);
+                });
+            }
+            context.expect_message_empty();
             context.expect_feedback_empty();
         })
         .await
     }
 
     #[tokio::test]
-    async fn protocol_pause_and_resume() {
+    async fn protocol_extend_beyond_limits() {
         let mut simulation = Simulation::new();
-        simulation.start(&[1.0, 4.0], 4.0);
-        with_context(simulation, |mut context| async move {
-            context.send_incoming_message(Event::Pause);
+        simulation.start(&[1.0, 2.0], 4.0);
+        let limits = Limits {
+            max_hours: 10.0,
+            ..Limits::default()
+        };
+        let protocol = Protocol::new(simulation).with_limits(limits);
+        with_protocol_context(protocol, |mut context| async move {
+            context.send_incoming_message(Event::Extend { hours: 7.0 });
 
-            sleep(Duration::from_millis(500)).await;
+            sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, _, _| {
-                assert!(!running);
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
             });
+            context.expect_message_empty();
+        })
+        .await
+    }
 
-            context.expect_feedback_empty();
+    #[tokio::test]
+    async fn protocol_forward_beyond_limits() {
+        let limits = Limits {
+            max_forward_work: 1000.0,
+            ..Limits::default()
+        };
+        let protocol = Protocol::new(Simulation::new()).with_limits(limits);
+        with_protocol_context(protocol, |mut context| async move {
+            let start = |forward_hours| Event::Start {
+                landscape: vec![1.0; 10],
+                hours: 4.0,
+                options: StartOptions {
+                    forward_hours: Some(forward_hours),
+                    ..StartOptions::default()
+                },
+            };
+            context.send_incoming_message(start(1000.0));
+            context.send_incoming_message(start(1.0));
 
-            context.send_incoming_message(Event::Resume);
+            sleep(Duration::from_millis(10)).await;
+
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
+            });
+            assert!(matches!(
+                context.receive_message(),
+                Some(Event::Progress { .. })
+            ));
+
+            context.send_incoming_message(Event::SetSpeed {
+                step_delay_millis: None,
+                forward_hours: Some(1000.0),
+            });
 
             sleep(Duration::from_millis(10)).await;
 
-            context.expect_progress_with(|running, _, _| {
-                assert!(running);
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidParams);
             });
+        })
+        .await
+    }
 
-            context.expect_feedback_with(|event| {
-                assert_eq!(event, Event::Step);
+    #[tokio::test]
+    async fn protocol_close_simulations_beyond_limits() {
+        let limits = Limits {
+            max_simulations: 2,
+            ..Limits::default()
+        };
+        let protocol = Protocol::new(Simulation::new()).with_limits(limits);
+        with_protocol_context(protocol, |mut context| async move {
+            let start = || Event::Start {
+                landscape: vec![1.0, 2.0],
+                hours: 4.0,
+                options: StartOptions::default(),
+            };
+            // The default simulation takes one of the places
+            context.send_incoming_envelope("a", start());
+            context.send_incoming_envelope("b", start());
+            context.send_incoming_envelope("a", Event::Close);
+            context.send_incoming_envelope("b", start());
+            context.send_incoming_envelope("a", Event::Close);
+
+            sleep(Duration::from_millis(10)).await;
+
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "a");
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
+            });
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "a");
+            assert_eq!(envelope.event, Event::Close);
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "b");
+            assert!(matches!(envelope.event, Event::Progress { .. }));
+            context.expect_error_with(|code, _| {
+                assert_eq!(code, ErrorCode::InvalidState);
             });
+            context.expect_message_empty();
+
+            // The steps scheduled by the closed simulation are ignored by the new one
+            context.send_incoming_text(r#"{"simulation":"b","event":"close","id":"close-1"}"#);
+            context.send_incoming_envelope("a", start());
+
+            sleep(Duration::from_millis(10)).await;
+
+            let envelope = context.receive_envelope().unwrap();
+            assert_eq!(envelope.simulation, "b");
+            assert_eq!(envelope.id, Some("close-1".to_string()));
+            assert_eq!(envelope.event, Event::Close);
+
+            let step = Envelope::new("a".to_string(), Event::Step).with_generation(1);
+            context.send_feedback_envelope(step);
+
+            sleep(Duration::from_millis(10)).await;
+
+            assert!(matches!(
+                context.receive_envelope().map(|envelope| envelope.simulation),
+                Some(simulation) if simulation == "a"
+            ));
+            context.expect_message_empty();
         })
         .await
     }
 
-    async fn with_context<F, FT, T>(simulation: Simulation, mut f: F) -> T
+    #[test]
+    fn limits_reject_non_finite_values() {
+        let limits = Limits::default();
+
+        assert!(limits.validate_start(&[1.0, 2.0], 1.0).is_ok());
+        assert!(limits.validate_start(&[1.0, f64::NAN], 1.0).is_err());
+        assert!(limits.validate_start(&[f64::INFINITY], 1.0).is_err());
+        assert!(limits.validate_start(&[1.0], f64::NAN).is_err());
+        assert!(limits.validate_start(&[1.0], f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn limits_bound_the_forward_work() {
+        let limits = Limits {
+            max_forward_work: 1000.0,
+            ..Limits::default()
+        };
+
+        assert!(limits.validate_forward(10, 1.0, 100.0, false).is_ok());
+        assert!(limits.validate_forward(10, 1.0, 101.0, false).is_err());
+        assert!(limits.validate_forward(10, 0.1, 100.0, false).is_err());
+        assert!(limits.validate_forward(10, 1.0, 20.0, true).is_ok());
+        assert!(limits.validate_forward(10, 1.0, 100.0, true).is_err());
+        assert!(limits
+            .validate_forward(10, 1.0, f64::INFINITY, false)
+            .is_err());
+    }
+
+    async fn with_context<F, FT, T>(simulation: Simulation, f: F) -> T
+    where
+        F: FnMut(Context) -> FT,
+        FT: Future<Output = T>,
+    {
+        with_protocol_context(Protocol::new(simulation), f).await
+    }
+
+    async fn with_protocol_context<F, FT, T>(mut protocol: Protocol, mut f: F) -> T
     where
         F: FnMut(Context) -> FT,
         FT: Future<Output = T>,
@@ -361,11 +3654,11 @@ mod tests {
         let (messages_tx, incoming_messages) =
             mpsc::channel::<Result<Message, WsError>>(CHANNEL_SIZE);
 
-        let (outgoing_feedback_loop, feedback_loop_rx) = mpsc::channel::<Event>(CHANNEL_SIZE);
-        let (feedback_loop_tx, incoming_feedback_loop) = mpsc::channel::<Event>(CHANNEL_SIZE);
+        let (outgoing_feedback_loop, feedback_loop_rx) = mpsc::channel::<Envelope>(CHANNEL_SIZE);
+        let (feedback_loop_tx, incoming_feedback_loop) = mpsc::channel::<Envelope>(CHANNEL_SIZE);
 
-        tokio::spawn(async {
-            Protocol::new(simulation)
+        tokio::spawn(async move {
+            protocol
                 .run(
                     outgoing_messages,
                     incoming_messages,
@@ -384,19 +3677,26 @@ mod tests {
         .await
     }
 
+    fn envelope_from_message(message: Message) -> Option<Envelope> {
+        match received_from_message(message) {
+            Some(Received::Request(envelope)) => Some(envelope),
+            _ => None,
+        }
+    }
+
     struct Context {
         message_tx: Sender<Result<Message, WsError>>,
         message_rx: Receiver<Message>,
-        feedback_loop_tx: Sender<Event>,
-        feedback_loop_rx: Receiver<Event>,
+        feedback_loop_tx: Sender<Envelope>,
+        feedback_loop_rx: Receiver<Envelope>,
     }
 
     impl Context {
         fn new(
             message_tx: Sender<Result<Message, WsError>>,
             message_rx: Receiver<Message>,
-            feedback_loop_tx: Sender<Event>,
-            feedback_loop_rx: Receiver<Event>,
+            feedback_loop_tx: Sender<Envelope>,
+            feedback_loop_rx: Receiver<Envelope>,
         ) -> Self {
             Self {
                 message_tx,
@@ -411,28 +3711,48 @@ mod tests {
             self.message_tx.try_send(Ok(message)).unwrap();
         }
 
+        fn send_incoming_message_binary(&mut self, bytes: Vec<u8>) {
+            self.message_tx
+                .try_send(Ok(Message::Binary(bytes)))
+                .unwrap();
+        }
+
+        fn send_incoming_text(&mut self, text: &str) {
+            let message = Message::Text(text.to_string());
+            self.message_tx.try_send(Ok(message)).unwrap();
+        }
+
+        fn send_incoming_envelope(&mut self, simulation: &str, event: Event) {
+            let envelope = Envelope::new(simulation.to_string(), event);
+            let message = serde_json::to_string(&envelope).map(Message::Text).unwrap();
+            self.message_tx.try_send(Ok(message)).unwrap();
+        }
+
+        fn receive_message(&mut self) -> Option<Event> {
+            self.receive_envelope().map(|envelope| envelope.event)
+        }
+
+        fn receive_raw_message(&mut self) -> Option<Message> {
+            self.message_rx.try_recv().ok()
+        }
+
+        fn receive_envelope(&mut self) -> Option<Envelope> {
+            self.receive_raw_message()
+                .and_then(|message| decode(&message))
+                .and_then(|value| Envelope::deserialize(value.ok()?).ok())
+        }
+
         fn expect_progress_with<F>(&mut self, f: F)
         where
             F: Fn(bool, f64, Vec<f64>),
         {
-            let event = self
-                .message_rx
-                .try_next()
-                .ok()
-                .flatten()
-                .and_then(|message| {
-                    message
-                        .to_text()
-                        .ok()
-                        .and_then(|text| serde_json::from_str::<Event>(text).ok())
-                });
-
-            match event {
+            match self.receive_message() {
                 Some(event) => {
                     if let Event::Progress {
                         running,
                         time,
                         levels,
+                        ..
                     } = event
                     {
                         f(running, time, levels)
@@ -444,6 +3764,47 @@ mod tests {
             }
         }
 
+        fn expect_progress_with_reason<F>(&mut self, f: F)
+        where
+            F: Fn(f64, Option<StepReason>),
+        {
+            match self.receive_message() {
+                Some(Event::Progress { time, reason, .. }) => f(time, reason),
+                Some(event) => panic!("Expected progress, but found {:?}", event),
+                None => panic!("Expected progress, but nothing found"),
+            }
+        }
+
+        fn expect_error_with<F>(&mut self, f: F)
+        where
+            F: Fn(ErrorCode, Option<String>),
+        {
+            match self.receive_message() {
+                Some(Event::Error {
+                    code, request_id, ..
+                }) => f(code, request_id),
+                Some(event) => panic!("Expected error, but found {:?}", event),
+                None => panic!("Expected error, but nothing found"),
+            }
+        }
+
+        fn expect_message_empty(&mut self) {
+            if let Some(event) = self.receive_message() {
+                panic!("Expected no message, but found {:?}", event);
+            }
+        }
+
+        fn expect_lakes_with<F>(&mut self, f: F)
+        where
+            F: Fn(f64, Vec<Lake>),
+        {
+            match self.receive_message() {
+                Some(Event::Lakes { time, lakes }) => f(time, lakes),
+                Some(event) => panic!("Expected lakes, but found {:?}", event),
+                None => panic!("Expected lakes, but nothing found"),
+            }
+        }
+
         fn expect_feedback_with<F>(&mut self, f: F)
         where
             F: Fn(Event),
@@ -461,11 +3822,20 @@ mod tests {
         }
 
         fn send_feedback(&mut self, event: Event) {
-            self.feedback_loop_tx.try_send(event).unwrap();
+            self.send_feedback_envelope(Envelope::new(SimulationId::default(), event));
+        }
+
+        fn send_feedback_envelope(&mut self, envelope: Envelope) {
+            self.feedback_loop_tx.try_send(envelope).unwrap();
         }
 
         fn receive_feedback(&mut self) -> Option<Event> {
-            self.feedback_loop_rx.try_next().ok().flatten()
+            self.receive_feedback_envelope()
+                .map(|envelope| envelope.event)
+        }
+
+        fn receive_feedback_envelope(&mut self) -> Option<Envelope> {
+            self.feedback_loop_rx.try_recv().ok()
         }
     }
 }
@@ -0,0 +1,378 @@
+use std::fs::File;
+use std::io::{BufRead, LineWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use futures::future;
+use futures::StreamExt;
+use futures_channel::{mpsc, oneshot};
+use futures_util::{stream::Stream, Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+use tungstenite::{Error as WsError, Message};
+
+use crate::encoding::decode;
+use crate::protocol::{Envelope, Event, Protocol, SimulationId};
+use crate::simulation::Simulation;
+
+const REPLAY_CHANNEL_SIZE: usize = 1024;
+
+/// Time to wait for the last outgoing events after the end of a recording
+const REPLAY_GRACE_MILLIS: u64 = 1000;
+
+/// The direction of a recorded event, from the point of view of the server
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// An event of a recording with the milliseconds elapsed since the recording started
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub millis: u64,
+    pub direction: Direction,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub simulation: SimulationId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub event: Event,
+}
+
+/// A message waiting for the thread of the recorder
+enum Record {
+    Message {
+        millis: u64,
+        direction: Direction,
+        message: Message,
+    },
+    /// Answered when the previous messages are written
+    Flush(oneshot::Sender<()>),
+}
+
+/// This writes every incoming event and every outgoing progress of a connection as JSON lines.
+///
+/// The messages are decoded and written by a thread, so the connection is not blocked by the writer.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    records: Sender<Record>,
+}
+
+impl Recorder {
+    pub fn new<W: Write + Send + 'static>(mut writer: W) -> Self {
+        let (records, received) = channel();
+        thread::spawn(move || {
+            for record in received {
+                match record {
+                    Record::Message {
+                        millis,
+                        direction,
+                        message,
+                    } => {
+                        if let Err(err) = write_message(&mut writer, millis, direction, &message) {
+                            log::warn!("Error recording event: {}", err);
+                        }
+                    }
+                    Record::Flush(written) => {
+                        if let Err(err) = writer.flush() {
+                            log::warn!("Error recording event: {}", err);
+                        }
+                        let _ = written.send(());
+                    }
+                }
+            }
+        });
+        Self {
+            start: Instant::now(),
+            records,
+        }
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::create(path)?;
+        Ok(Self::new(LineWriter::new(file)))
+    }
+
+    fn record_message(&self, direction: Direction, message: &Message) {
+        let record = Record::Message {
+            millis: self.start.elapsed().as_millis() as u64,
+            direction,
+            message: message.clone(),
+        };
+        // The thread only stops when every recorder is dropped
+        let _ = self.records.send(record);
+    }
+
+    /// Wait until the messages recorded so far are written
+    pub async fn flush(&self) {
+        let (written, flushed) = oneshot::channel();
+        if self.records.send(Record::Flush(written)).is_ok() {
+            let _ = flushed.await;
+        }
+    }
+
+    /// Wrap a stream of incoming messages to record them as they are received
+    pub fn incoming<S, E>(&self, messages: S) -> impl Stream<Item = Result<Message, E>>
+    where
+        S: Stream<Item = Result<Message, E>>,
+    {
+        let recorder = self.clone();
+        messages.inspect(move |try_message| {
+            if let Ok(message) = try_message {
+                recorder.record_message(Direction::Incoming, message);
+            }
+        })
+    }
+
+    /// Wrap a sink of outgoing messages to record them as they are sent
+    pub fn outgoing<S, E>(&self, messages: S) -> impl Sink<Message, Error = E>
+    where
+        S: Sink<Message, Error = E>,
+    {
+        let recorder = self.clone();
+        messages.with(move |message: Message| {
+            recorder.record_message(Direction::Outgoing, &message);
+            future::ready(Ok::<_, E>(message))
+        })
+    }
+}
+
+/// Only the outgoing progress is recorded, as the rest of outgoing events can be derived from it
+fn write_message<W: Write>(
+    writer: &mut W,
+    millis: u64,
+    direction: Direction,
+    message: &Message,
+) -> Result<()> {
+    let envelope = match decode(message)
+        .and_then(Result::ok)
+        .and_then(|value| Envelope::deserialize(value).ok())
+    {
+        Some(envelope) => envelope,
+        None => return Ok(()),
+    };
+    let recorded = direction == Direction::Incoming
+        || matches!(
+            envelope.event,
+            Event::Progress { .. }
+                | Event::ProgressDelta { .. }
+                | Event::ViewProgress { .. }
+                | Event::ComparisonProgress { .. }
+        );
+    if !recorded {
+        return Ok(());
+    }
+    let entry = Entry {
+        millis,
+        direction,
+        simulation: envelope.simulation,
+        id: envelope.id,
+        event: envelope.event,
+    };
+    writeln!(writer, "{}", serde_json::to_string(&entry)?)?;
+    Ok(())
+}
+
+/// Read the entries of a recording, one per line
+pub fn read_recording<R: BufRead>(reader: R) -> Result<Vec<Entry>> {
+    reader
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str::<Entry>(line?.as_str())?))
+        .collect()
+}
+
+/// Feed the incoming events of a recording to a new simulation with the same timing,
+/// and record the new run, so it can be compared with the original one.
+pub async fn replay(entries: Vec<Entry>, recorder: Recorder) -> Result<()> {
+    let duration = entries.last().map(|entry| entry.millis).unwrap_or(0);
+
+    let (outgoing_messages, messages_rx) = mpsc::channel::<Message>(REPLAY_CHANNEL_SIZE);
+    let (mut messages_tx, incoming_messages) =
+        mpsc::channel::<Result<Message, WsError>>(REPLAY_CHANNEL_SIZE);
+    let (outgoing_feedback_loop, incoming_feedback_loop) =
+        mpsc::channel::<Envelope>(REPLAY_CHANNEL_SIZE);
+
+    let outgoing_messages = Box::pin(recorder.outgoing(outgoing_messages));
+    let incoming_messages = Box::pin(recorder.incoming(incoming_messages));
+    let protocol = tokio::spawn(async {
+        Protocol::new(Simulation::new())
+            .run(
+                outgoing_messages,
+                incoming_messages,
+                outgoing_feedback_loop,
+                incoming_feedback_loop,
+            )
+            .await
+    });
+    let consumer = tokio::spawn(messages_rx.for_each(|_| future::ready(())));
+
+    let start = Instant::now();
+    for entry in entries {
+        if entry.direction != Direction::Incoming {
+            continue;
+        }
+        let elapsed = start.elapsed();
+        let target = Duration::from_millis(entry.millis);
+        if target > elapsed {
+            sleep(target - elapsed).await;
+        }
+        let envelope = Envelope::new(entry.simulation, entry.event).with_id(entry.id);
+        let message = Message::Text(serde_json::to_string(&envelope)?);
+        messages_tx.send(Ok(message)).await?;
+    }
+
+    let elapsed = start.elapsed();
+    let target = Duration::from_millis(duration + REPLAY_GRACE_MILLIS);
+    if target > elapsed {
+        sleep(target - elapsed).await;
+    }
+
+    protocol.abort();
+    consumer.abort();
+    recorder.flush().await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::protocol::StartOptions;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn entries(&self) -> Vec<Entry> {
+            let bytes = self.0.lock().unwrap().clone();
+            read_recording(Cursor::new(bytes)).unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn recorder_records_incoming_events_and_outgoing_progress() {
+        let buffer = SharedBuffer::default();
+        let recorder = Recorder::new(buffer.clone());
+
+        let pause = Message::Text(serde_json::to_string(&Event::Pause).unwrap());
+        let progress = Event::Progress {
+            running: false,
+            time: 0.5,
+            levels: vec![1.0],
+            reason: None,
+        };
+        let lakes = Event::Lakes {
+            time: 0.5,
+            lakes: vec![],
+        };
+        recorder.record_message(Direction::Incoming, &pause);
+        recorder.record_message(Direction::Incoming, &Message::Text("invalid".to_string()));
+        recorder.record_message(
+            Direction::Outgoing,
+            &Message::Text(serde_json::to_string(&progress).unwrap()),
+        );
+        recorder.record_message(
+            Direction::Outgoing,
+            &Message::Text(serde_json::to_string(&lakes).unwrap()),
+        );
+        recorder.flush().await;
+
+        let entries = buffer.entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::Incoming);
+        assert_eq!(entries[0].event, Event::Pause);
+        assert_eq!(entries[1].direction, Direction::Outgoing);
+        assert_eq!(entries[1].event, progress);
+        assert!(entries[0].millis <= entries[1].millis);
+    }
+
+    #[test]
+    fn read_recording_skips_empty_lines() {
+        let recording =
+            "{\"millis\":0,\"direction\":\"incoming\",\"event\":{\"event\":\"pause\"}}\n\n";
+
+        let entries = read_recording(Cursor::new(recording)).unwrap();
+
+        assert_eq!(
+            entries,
+            vec![Entry {
+                millis: 0,
+                direction: Direction::Incoming,
+                simulation: SimulationId::default(),
+                id: None,
+                event: Event::Pause,
+            }]
+        );
+    }
+
+    #[test]
+    fn read_recording_with_invalid_lines() {
+        assert!(read_recording(Cursor::new("invalid\n")).is_err());
+    }
+
+    #[tokio::test]
+    async fn replay_recording() {
+        let entries = vec![
+            Entry {
+                millis: 0,
+                direction: Direction::Incoming,
+                simulation: SimulationId::default(),
+                id: None,
+                event: Event::Start {
+                    landscape: vec![1.0, 2.0],
+                    hours: 0.2,
+                    options: StartOptions {
+                        step_delay_millis: Some(10),
+                        ..StartOptions::default()
+                    },
+                },
+            },
+            Entry {
+                millis: 100,
+                direction: Direction::Outgoing,
+                simulation: SimulationId::default(),
+                id: None,
+                event: Event::Progress {
+                    running: false,
+                    time: 0.2,
+                    levels: vec![2.2, 2.2],
+                    reason: None,
+                },
+            },
+        ];
+        let buffer = SharedBuffer::default();
+
+        replay(entries, Recorder::new(buffer.clone()))
+            .await
+            .unwrap();
+
+        let entries = buffer.entries();
+        assert_eq!(entries.len(), 4);
+        assert!(matches!(entries[0].event, Event::Start { .. }));
+        assert!(entries[1..]
+            .iter()
+            .all(|entry| entry.direction == Direction::Outgoing));
+        assert!(matches!(
+            entries[3].event,
+            Event::Progress { running: false, .. }
+        ));
+    }
+}
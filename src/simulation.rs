@@ -1,15 +1,103 @@
-use crate::water_flow::WaterFlow;
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "fixed-point")]
+use crate::fixed_point::Fixed;
+use crate::water_flow::{Lake, WaterFlow};
 
 pub(crate) const DELTA_TIME: f64 = 0.1;
 
+/// The version of the snapshots format, which must be increased when the inputs of the simulation change
+const SNAPSHOT_VERSION: u32 = 2;
+
+/// The default depth of water that the rain adds to every segment in one hour
+const RAIN_RATE: f64 = 1.0;
+
+/// The precision in hours used to find the time of the events in the adaptive mode
+pub(crate) const EVENT_TIME_TOLERANCE: f64 = 1e-3;
+
+/// The shortest phase of a schedule accepted from the clients
+pub(crate) const MIN_PHASE_HOURS: f64 = 0.01;
+
+/// The precision in levels used to check whether a lake reached its spill point
+const SPILL_LEVEL_TOLERANCE: f64 = 1e-6;
+
+/// The volumes of water are represented with fixed-point arithmetic when reproducible results are required
+#[cfg(feature = "fixed-point")]
+type WaterVolume = Fixed;
+#[cfg(not(feature = "fixed-point"))]
+type WaterVolume = f64;
+
+/// The weather during a phase of the schedule
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weather {
+    Rain,
+    Dry,
+}
+
+/// A phase of the schedule with the same weather for some hours
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    pub weather: Weather,
+    pub hours: f64,
+}
+
+/// The reason why the clock stopped at the current time of the simulation
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepReason {
+    /// The clock moved forward by the configured interval of time
+    Interval,
+    /// A lake reached the spill point of its sink
+    SinkFilled,
+    /// Some lakes merged into a bigger one
+    LakesMerged,
+    /// The clock jumped to a different time
+    Seek,
+}
+
+/// A summary of the lakes with their region and whether they are full,
+/// and the number of full sinks, used to detect events between steps
+struct LakesSummary {
+    lakes: Vec<(usize, usize, bool)>,
+    full_sinks: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct Simulation {
     hours: f64,
     landscape: Vec<f64>,
     running: bool,
     fast_forward: bool,
+    rewind: bool,
     delta_time: f64,
+    adaptive: bool,
     time: f64,
-    water_levels: WaterFlow,
+    reason: Option<StepReason>,
+    schedule: Vec<Phase>,
+    rain_rate: f64,
+    loss_rate: f64,
+    water_levels: WaterFlow<u32, WaterVolume>,
+}
+
+/// The inputs of a simulation at a certain moment, so it can be restored later.
+///
+/// The state of the water is not included, as it is calculated again from the landscape and the time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    pub landscape: Vec<f64>,
+    pub hours: f64,
+    pub time: f64,
+    pub running: bool,
+    pub fast_forward: bool,
+    pub rewind: bool,
+    pub adaptive: bool,
+    pub delta_time: f64,
+    pub schedule: Vec<Phase>,
+    pub rain_rate: f64,
+    pub loss_rate: f64,
 }
 
 impl Simulation {
@@ -19,8 +107,14 @@ impl Simulation {
             landscape: vec![],
             running: false,
             fast_forward: false,
+            rewind: false,
             delta_time: DELTA_TIME,
+            adaptive: false,
             time: 0.0,
+            reason: None,
+            schedule: vec![],
+            rain_rate: RAIN_RATE,
+            loss_rate: 0.0,
             water_levels: WaterFlow::new(vec![]),
         }
     }
@@ -30,41 +124,297 @@ impl Simulation {
         self.landscape = Vec::from(landscape);
         self.running = true;
         self.fast_forward = false;
+        self.rewind = false;
         self.time = 0.0;
+        self.reason = None;
         let terrain = landscape.iter().map(|segment| *segment as u32).collect();
         self.water_levels = WaterFlow::new(terrain);
     }
 
+    /// Set the simulated hours for every step
+    pub fn set_delta_time(&mut self, delta_time: f64) {
+        self.delta_time = delta_time;
+    }
+
+    /// Enable or disable the adaptive mode, which moves the clock forward to the next event
+    /// of the hierarchy of sinks (a sink filling or lakes merging), or by the delta time at most.
+    pub fn set_adaptive(&mut self, adaptive: bool) {
+        self.adaptive = adaptive;
+    }
+
+    /// Set the schedule of rain and dry phases, which is repeated cyclically until the end of the simulation.
+    /// Without a schedule it will be raining all the time. Phases without a positive duration are ignored.
+    pub fn set_schedule(&mut self, schedule: Vec<Phase>) {
+        self.schedule = schedule
+            .into_iter()
+            .filter(|phase| phase.hours > 0.0)
+            .collect();
+    }
+
+    /// Set the depth of water per hour that the rain adds to every segment during the rain phases
+    pub fn set_rain_rate(&mut self, rain_rate: f64) {
+        self.rain_rate = rain_rate.max(0.0);
+    }
+
+    /// Set the depth of water per hour that the landscape loses (by outflow, evaporation or infiltration)
+    pub fn set_loss_rate(&mut self, loss_rate: f64) {
+        self.loss_rate = loss_rate.max(0.0);
+    }
+
     pub fn pause(&mut self) {
         self.running = false;
         self.fast_forward = false;
+        self.rewind = false;
     }
 
     pub fn resume(&mut self) {
         self.running = !self.is_finished();
         self.fast_forward = false;
+        self.rewind = false;
     }
 
     pub fn step(&mut self) {
         let remaining_time = (self.hours - self.time).clamp(0.0, self.hours);
         let delta_time = f64::min(self.delta_time, remaining_time);
-        self.time += delta_time;
-        self.water_levels.rain(self.time);
+        if self.adaptive && delta_time > 0.0 {
+            let (time, reason) = self.find_next_event(self.time + delta_time);
+            self.time = time;
+            self.reason = Some(reason);
+        } else {
+            self.time += delta_time;
+            self.water_levels.rain(self.water_depth_at(self.time));
+            self.reason = Some(StepReason::Interval);
+        }
         self.running = !self.is_finished();
     }
 
     pub fn start_forward(&mut self) {
         self.fast_forward = !self.is_finished();
+        self.rewind = false;
         self.running = !self.is_finished();
     }
 
+    pub fn start_rewind(&mut self) {
+        self.rewind = !self.is_at_beginning();
+        self.fast_forward = false;
+        self.running = !self.is_at_beginning();
+    }
+
+    /// Move the clock backwards by the delta time, draining the water that fell in that interval
+    pub fn step_back(&mut self) {
+        self.time = f64::max(self.time - self.delta_time, 0.0);
+        self.water_levels.rain(self.water_depth_at(self.time));
+        self.reason = Some(StepReason::Interval);
+        self.running = !self.is_at_beginning();
+        self.rewind = self.rewind && self.running;
+    }
+
+    /// Add more hours to the simulation.
+    /// If it was already finished, it continues playing from the current state.
+    pub fn extend(&mut self, hours: f64) {
+        if hours <= 0.0 {
+            return;
+        }
+        if self.is_finished() {
+            self.running = true;
+            self.fast_forward = false;
+            self.rewind = false;
+        }
+        self.hours += hours;
+    }
+
+    /// Jump to any time between the beginning and the end of the simulation.
+    /// The state of the water can be calculated for any time, so this is as cheap as a single step.
+    pub fn seek(&mut self, time: f64) {
+        self.time = time.clamp(0.0, self.hours.max(0.0));
+        self.water_levels.rain(self.water_depth_at(self.time));
+        self.reason = Some(StepReason::Seek);
+        self.running = self.running && !self.is_finished();
+    }
+
+    /// Simulate some hours in a row.
+    /// In the adaptive mode it stops earlier when an event happens, so it can be reported.
     pub fn forward(&mut self, hours: f64) {
         let start = self.get_time();
         while !self.is_finished() && self.get_time() - start < hours {
             self.step();
+            if self.reason != Some(StepReason::Interval) {
+                break;
+            }
         }
     }
 
+    /// Find the time of the next event between the current time and a target time.
+    /// The water state is updated for that time, so it can be bisected until finding the event.
+    fn find_next_event(&mut self, target: f64) -> (f64, StepReason) {
+        let before = self.lakes_summary();
+
+        self.water_levels.rain(self.water_depth_at(target));
+        let mut reason = match Self::detect_event(&before, &self.lakes_summary()) {
+            Some(reason) => reason,
+            None => return (target, StepReason::Interval),
+        };
+
+        let (mut low, mut high) = (self.time, target);
+        while high - low > EVENT_TIME_TOLERANCE {
+            let middle = (low + high) / 2.0;
+            self.water_levels.rain(self.water_depth_at(middle));
+            match Self::detect_event(&before, &self.lakes_summary()) {
+                Some(middle_reason) => {
+                    high = middle;
+                    reason = middle_reason;
+                }
+                None => low = middle,
+            }
+        }
+
+        self.water_levels.rain(self.water_depth_at(high));
+        (high, reason)
+    }
+
+    fn lakes_summary(&self) -> LakesSummary {
+        let lakes = self
+            .water_levels
+            .lakes()
+            .iter()
+            .map(|lake| {
+                let full = matches!(&lake.spill, Some(spill)
+                    if lake.surface >= spill.elevation as f64 - SPILL_LEVEL_TOLERANCE);
+                (lake.start, lake.end, full)
+            })
+            .collect();
+        LakesSummary {
+            lakes,
+            full_sinks: self.water_levels.full_sinks(),
+        }
+    }
+
+    /// Compare the lakes from two different times to find out whether some of them merged or got full.
+    ///
+    /// A sink can get full and spill into its parent within the same step, so its lake is never seen at the spill point.
+    /// That's why the full sinks of the hierarchy are counted too.
+    fn detect_event(before: &LakesSummary, after: &LakesSummary) -> Option<StepReason> {
+        let merged = after.lakes.iter().any(|(start, end, _)| {
+            before
+                .lakes
+                .iter()
+                .filter(|(other_start, other_end, _)| other_start >= start && other_end <= end)
+                .count()
+                > 1
+        });
+
+        let full_before = before.lakes.iter().filter(|(_, _, full)| *full).count();
+        let full_after = after.lakes.iter().filter(|(_, _, full)| *full).count();
+
+        if merged {
+            Some(StepReason::LakesMerged)
+        } else if full_after > full_before || after.full_sinks > before.full_sinks {
+            Some(StepReason::SinkFilled)
+        } else {
+            None
+        }
+    }
+
+    /// Calculate the depth of water accumulated by the landscape at a certain time.
+    /// The rain adds water during the rain phases, while the losses drain it during the whole simulation.
+    /// The losses are distributed evenly across the landscape, so the water drains from the top of the sinks.
+    fn water_depth_at(&self, time: f64) -> f64 {
+        if self.schedule.is_empty() {
+            return f64::max((self.rain_rate - self.loss_rate) * time, 0.0);
+        }
+
+        let change = |phase: &Phase, hours: f64| {
+            let rain_rate = match phase.weather {
+                Weather::Rain => self.rain_rate,
+                Weather::Dry => 0.0,
+            };
+            (rain_rate - self.loss_rate) * hours
+        };
+
+        // A whole cycle turns a depth `d` into `max(d + gain, floor)`, as the depth can not be negative,
+        // so `n` cycles turn an empty landscape into `max(n * gain, floor + max((n - 1) * gain, 0))`
+        let (gain, floor) = self
+            .schedule
+            .iter()
+            .fold((0.0, 0.0), |(gain, floor), phase| {
+                let change = change(phase, phase.hours);
+                (gain + change, f64::max(floor + change, 0.0))
+            });
+        let cycle_hours: f64 = self.schedule.iter().map(|phase| phase.hours).sum();
+        let cycles = (time / cycle_hours).floor();
+        let mut depth = if cycles >= 1.0 {
+            f64::max(cycles * gain, floor + f64::max((cycles - 1.0) * gain, 0.0))
+        } else {
+            0.0
+        };
+
+        let mut elapsed = cycles * cycle_hours;
+        for phase in self.schedule.iter() {
+            if elapsed >= time {
+                break;
+            }
+            let hours = f64::min(phase.hours, time - elapsed);
+            depth = f64::max(depth + change(phase, hours), 0.0);
+            elapsed += hours;
+        }
+        depth
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            landscape: self.landscape.clone(),
+            hours: self.hours,
+            time: self.time,
+            running: self.running,
+            fast_forward: self.fast_forward,
+            rewind: self.rewind,
+            adaptive: self.adaptive,
+            delta_time: self.delta_time,
+            schedule: self.schedule.clone(),
+            rain_rate: self.rain_rate,
+            loss_rate: self.loss_rate,
+        }
+    }
+
+    /// Build a simulation from a snapshot, as long as it was taken with the same version of the format.
+    /// The water is simulated again up to the time of the snapshot, so the hierarchy of sinks is always consistent with the landscape.
+    ///
+    /// The landscape and the settings are expected to be validated like the ones of a new simulation.
+    pub fn restore(snapshot: Snapshot) -> Result<Simulation> {
+        ensure!(
+            snapshot.version == SNAPSHOT_VERSION,
+            "unsupported snapshot version {} (expected {})",
+            snapshot.version,
+            SNAPSHOT_VERSION
+        );
+        ensure!(
+            snapshot.time >= 0.0 && snapshot.time <= snapshot.hours,
+            "time must be in the range [0, hours]"
+        );
+        ensure!(
+            snapshot.rain_rate >= 0.0 && snapshot.rain_rate.is_finite(),
+            "rain_rate can not be negative"
+        );
+
+        let mut simulation = Simulation::new();
+        simulation.set_delta_time(snapshot.delta_time);
+        simulation.set_adaptive(snapshot.adaptive);
+        simulation.set_schedule(snapshot.schedule);
+        simulation.set_rain_rate(snapshot.rain_rate);
+        simulation.set_loss_rate(snapshot.loss_rate);
+        simulation.start(&snapshot.landscape, snapshot.hours);
+
+        simulation.time = snapshot.time;
+        simulation
+            .water_levels
+            .rain(simulation.water_depth_at(snapshot.time));
+        simulation.running = snapshot.running && !simulation.is_finished();
+        simulation.fast_forward = snapshot.fast_forward && simulation.running;
+        simulation.rewind = snapshot.rewind && !simulation.fast_forward && simulation.running;
+        Ok(simulation)
+    }
+
     #[inline]
     pub fn is_running(&self) -> bool {
         self.running
@@ -75,20 +425,71 @@ impl Simulation {
         self.fast_forward
     }
 
+    #[inline]
+    pub fn is_started(&self) -> bool {
+        !self.landscape.is_empty()
+    }
+
+    #[inline]
+    pub fn is_rewind(&self) -> bool {
+        self.rewind
+    }
+
+    #[inline]
+    pub fn is_at_beginning(&self) -> bool {
+        self.time <= 0.0
+    }
+
     #[inline]
     pub fn is_finished(&self) -> bool {
         self.time >= self.hours
     }
 
+    #[inline]
+    pub fn is_adaptive(&self) -> bool {
+        self.adaptive
+    }
+
+    #[inline]
+    pub fn get_segments(&self) -> usize {
+        self.landscape.len()
+    }
+
+    #[inline]
+    pub fn get_delta_time(&self) -> f64 {
+        self.delta_time
+    }
+
+    #[inline]
+    pub fn get_hours(&self) -> f64 {
+        self.hours
+    }
+
     #[inline]
     pub fn get_time(&self) -> f64 {
         self.time
     }
 
+    #[inline]
+    pub fn get_reason(&self) -> Option<StepReason> {
+        self.reason
+    }
+
+    /// Return the reason of the last step and forget it, so it is only reported once
+    #[inline]
+    pub fn take_reason(&mut self) -> Option<StepReason> {
+        self.reason.take()
+    }
+
     #[inline]
     pub fn get_levels(&self) -> Vec<f64> {
         self.water_levels.total_levels()
     }
+
+    #[inline]
+    pub fn get_lakes(&self) -> Vec<Lake> {
+        self.water_levels.lakes()
+    }
 }
 
 #[cfg(test)]
@@ -124,6 +525,308 @@ pub mod tests {
         assert_slice_approx_eq(sim.get_levels().as_slice(), &[1.0, 2.0, 3.0, 4.0]);
     }
 
+    #[test]
+    fn simulation_step_with_custom_delta_time() {
+        let mut sim = Simulation::new();
+        sim.set_delta_time(0.5);
+        sim.start(&[1.0, 1.0], 4.0);
+
+        sim.step();
+
+        assert_approx_eq!(sim.get_time(), 0.5);
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[1.5, 1.5]);
+    }
+
+    #[test]
+    fn simulation_step_tags_the_reason() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 4.0);
+
+        assert_eq!(sim.get_reason(), None);
+
+        sim.step();
+
+        assert_eq!(sim.get_reason(), Some(StepReason::Interval));
+    }
+
+    #[test]
+    fn simulation_adaptive_step_stops_at_the_events() {
+        let mut sim = Simulation::new();
+        sim.set_adaptive(true);
+        sim.set_delta_time(1.0);
+        sim.start(&[2.0, 6.0, 5.0, 9.0], 4.0);
+
+        let mut steps = vec![];
+        for _ in 0..4 {
+            sim.step();
+            steps.push((sim.get_time(), sim.get_reason().unwrap()));
+        }
+
+        assert_approx_eq!(steps[0].0, 0.5, 0.01);
+        assert_eq!(steps[0].1, StepReason::SinkFilled);
+        assert_approx_eq!(steps[1].0, 1.25, 0.01);
+        assert_eq!(steps[1].1, StepReason::SinkFilled);
+        assert_approx_eq!(steps[2].0, 1.25, 0.01);
+        assert_eq!(steps[2].1, StepReason::LakesMerged);
+        assert_approx_eq!(steps[3].0, steps[2].0 + 1.0);
+        assert_eq!(steps[3].1, StepReason::Interval);
+
+        let level = 6.0 + (4.0 * sim.get_time() - 5.0) / 3.0;
+        assert_slice_approx_eq_with_epsilon(
+            sim.get_levels().as_slice(),
+            &[level, level, level, 9.0],
+            0.01,
+        );
+    }
+
+    #[test]
+    fn simulation_adaptive_step_stops_when_a_sink_spills_into_its_parent() {
+        let mut sim = Simulation::new();
+        sim.set_adaptive(true);
+        sim.set_delta_time(1.0);
+        sim.start(&[1.0, 3.0, 5.0], 2.0);
+
+        // The first sink gets full at 2/3 and spills into its parent before the end of the step,
+        // so its lake is never seen at the spill point
+        sim.step();
+
+        assert_approx_eq!(sim.get_time(), 2.0 / 3.0, 0.01);
+        assert_eq!(sim.get_reason(), Some(StepReason::SinkFilled));
+    }
+
+    #[test]
+    fn simulation_adaptive_forward_stops_at_the_events() {
+        let mut sim = Simulation::new();
+        sim.set_adaptive(true);
+        sim.start(&[2.0, 6.0, 5.0, 9.0], 4.0);
+
+        sim.forward(2.0);
+
+        assert_approx_eq!(sim.get_time(), 0.5, 0.01);
+        assert_eq!(sim.get_reason(), Some(StepReason::SinkFilled));
+        assert!(sim.is_running());
+    }
+
+    #[test]
+    fn simulation_adaptive_step_finishes() {
+        let mut sim = Simulation::new();
+        sim.set_adaptive(true);
+        sim.start(&[1.0, 1.0], DELTA_TIME);
+
+        sim.step();
+
+        assert!(sim.is_finished());
+        assert_approx_eq!(sim.get_time(), DELTA_TIME);
+        assert_eq!(sim.get_reason(), Some(StepReason::Interval));
+    }
+
+    #[test]
+    fn simulation_seek_forward_and_backwards() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 8.0], 4.0);
+
+        sim.seek(3.0);
+
+        assert_approx_eq!(sim.get_time(), 3.0);
+        assert_eq!(sim.get_reason(), Some(StepReason::Seek));
+        assert!(sim.is_running());
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[7.0, 8.0]);
+
+        sim.seek(1.0);
+
+        assert_approx_eq!(sim.get_time(), 1.0);
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[3.0, 8.0]);
+    }
+
+    #[test]
+    fn simulation_seek_is_clamped() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 4.0);
+
+        sim.seek(-1.0);
+        assert_approx_eq!(sim.get_time(), 0.0);
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[1.0, 1.0]);
+
+        sim.seek(6.0);
+        assert_approx_eq!(sim.get_time(), 4.0);
+        assert!(sim.is_finished());
+        assert!(!sim.is_running());
+    }
+
+    #[test]
+    fn simulation_seek_backwards_when_finished() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 1.0);
+        sim.forward(1.0);
+
+        sim.seek(0.5);
+
+        assert!(!sim.is_finished());
+        assert!(!sim.is_running());
+
+        sim.resume();
+
+        assert!(sim.is_running());
+    }
+
+    #[test]
+    fn simulation_step_back() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 8.0], 4.0);
+        sim.seek(3.0);
+        sim.start_rewind();
+
+        assert!(sim.is_running());
+        assert!(sim.is_rewind());
+
+        sim.step_back();
+
+        assert_approx_eq!(sim.get_time(), 2.9);
+        assert_eq!(sim.get_reason(), Some(StepReason::Interval));
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[6.8, 8.0]);
+    }
+
+    #[test]
+    fn simulation_step_back_stops_at_the_beginning() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 4.0);
+        sim.step();
+        sim.start_rewind();
+
+        sim.step_back();
+
+        assert_approx_eq!(sim.get_time(), 0.0);
+        assert!(sim.is_at_beginning());
+        assert!(!sim.is_running());
+        assert!(!sim.is_rewind());
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn simulation_start_rewind_at_the_beginning() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 4.0);
+
+        sim.start_rewind();
+
+        assert!(!sim.is_running());
+        assert!(!sim.is_rewind());
+    }
+
+    #[test]
+    fn simulation_resume_after_rewind() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 4.0);
+        sim.forward(1.0);
+        sim.start_rewind();
+
+        sim.resume();
+
+        assert!(sim.is_running());
+        assert!(!sim.is_rewind());
+    }
+
+    #[test]
+    fn simulation_extend_when_finished() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 8.0], 1.0);
+        sim.start_forward();
+        sim.forward(1.0);
+
+        assert!(sim.is_finished());
+
+        sim.extend(1.0);
+
+        assert!(!sim.is_finished());
+        assert!(sim.is_running());
+        assert!(!sim.is_fast_forward());
+
+        sim.forward(1.0);
+
+        assert!(sim.is_finished());
+        assert_slice_approx_eq_with_epsilon(sim.get_levels().as_slice(), &[5.0, 8.0], 0.5);
+    }
+
+    #[test]
+    fn simulation_extend_when_paused() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 1.0], 1.0);
+        sim.pause();
+
+        sim.extend(2.0);
+        sim.extend(-1.0);
+
+        assert!(!sim.is_running());
+        sim.seek(3.0);
+        assert_approx_eq!(sim.get_time(), 3.0);
+    }
+
+    #[test]
+    fn simulation_snapshot_and_restore() {
+        let mut sim = Simulation::new();
+        sim.set_schedule(vec![Phase {
+            weather: Weather::Rain,
+            hours: 1.0,
+        }]);
+        sim.start(&[3.0, 1.0, 6.0, 2.0], 4.0);
+        sim.seek(1.5);
+        sim.start_forward();
+
+        let json = serde_json::to_string(&sim.snapshot()).unwrap();
+        let restored = Simulation::restore(serde_json::from_str(&json).unwrap()).unwrap();
+
+        assert_eq!(restored.snapshot(), sim.snapshot());
+        assert!(restored.is_running());
+        assert!(restored.is_fast_forward());
+        assert_approx_eq!(restored.get_time(), 1.5);
+        assert_slice_approx_eq(
+            restored.get_levels().as_slice(),
+            sim.get_levels().as_slice(),
+        );
+    }
+
+    #[test]
+    fn simulation_snapshot_without_the_water() {
+        let mut sim = Simulation::new();
+        sim.start(&[3.0, 1.0], 4.0);
+
+        let json = serde_json::to_value(sim.snapshot()).unwrap();
+
+        assert!(json.get("water_levels").is_none());
+        assert_eq!(json["landscape"], serde_json::json!([3.0, 1.0]));
+    }
+
+    #[test]
+    fn simulation_restore_with_invalid_time() {
+        let mut sim = Simulation::new();
+        sim.start(&[3.0, 1.0], 4.0);
+
+        for time in [-1.0, 5.0, f64::NAN] {
+            let mut snapshot = sim.snapshot();
+            snapshot.time = time;
+            assert!(Simulation::restore(snapshot).is_err());
+        }
+    }
+
+    #[test]
+    fn simulation_restore_unsupported_version() {
+        let mut snapshot = Simulation::new().snapshot();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+
+        assert!(Simulation::restore(snapshot).is_err());
+    }
+
+    #[test]
+    fn simulation_with_rain_rate() {
+        let mut sim = Simulation::new();
+        sim.set_rain_rate(2.0);
+        sim.start(&[1.0, 1.0], 1.0);
+
+        sim.seek(1.0);
+
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[3.0, 3.0]);
+    }
+
     #[test]
     fn simulation_pause() {
         let mut sim = Simulation::new();
@@ -306,6 +1009,136 @@ pub mod tests {
         assert!(sim.is_finished());
     }
 
+    #[test]
+    fn simulation_lakes_follow_the_steps() {
+        let mut sim = Simulation::new();
+        sim.start(&[1.0, 8.0, 1.0], 1.0);
+
+        assert!(sim.get_lakes().is_empty());
+
+        sim.step();
+
+        let lakes = sim.get_lakes();
+        assert_eq!(lakes.len(), 2);
+        assert_approx_eq!(lakes[0].surface, 1.0 + 1.5 * DELTA_TIME);
+        assert_approx_eq!(lakes[1].surface, 1.0 + 1.5 * DELTA_TIME);
+    }
+
+    #[test]
+    fn simulation_schedule_stops_the_rain_during_dry_phases() {
+        let mut sim = Simulation::new();
+        sim.set_schedule(vec![
+            Phase {
+                weather: Weather::Rain,
+                hours: 1.0,
+            },
+            Phase {
+                weather: Weather::Dry,
+                hours: 1.0,
+            },
+        ]);
+        sim.start(&[1.0, 1.0], 2.0);
+
+        assert_approx_eq!(sim.water_depth_at(0.5), 0.5);
+        assert_approx_eq!(sim.water_depth_at(1.5), 1.0);
+        assert_approx_eq!(sim.water_depth_at(2.5), 1.5);
+        assert_approx_eq!(sim.water_depth_at(4.0), 2.0);
+
+        sim.forward(2.0);
+
+        assert!(sim.is_finished());
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[2.0, 2.0]);
+    }
+
+    #[test]
+    fn simulation_losses_drain_the_water_during_dry_phases() {
+        let mut sim = Simulation::new();
+        sim.set_schedule(vec![
+            Phase {
+                weather: Weather::Rain,
+                hours: 2.0,
+            },
+            Phase {
+                weather: Weather::Dry,
+                hours: 4.0,
+            },
+        ]);
+        sim.set_loss_rate(0.5);
+        sim.start(&[1.0, 8.0], 6.0);
+
+        assert_approx_eq!(sim.water_depth_at(2.0), 1.0);
+        assert_approx_eq!(sim.water_depth_at(3.0), 0.5);
+        assert_approx_eq!(sim.water_depth_at(5.0), 0.0);
+        assert_approx_eq!(sim.water_depth_at(7.0), 0.5);
+
+        sim.forward(6.0);
+
+        assert!(sim.is_finished());
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[1.0, 8.0]);
+    }
+
+    #[test]
+    fn simulation_schedule_with_many_cycles() {
+        let mut sim = Simulation::new();
+        sim.set_schedule(vec![
+            Phase {
+                weather: Weather::Rain,
+                hours: 2.0,
+            },
+            Phase {
+                weather: Weather::Dry,
+                hours: 1.0,
+            },
+        ]);
+        sim.set_loss_rate(0.5);
+        sim.start(&[1.0, 1.0], 1e9);
+
+        assert_approx_eq!(sim.water_depth_at(3.0 * 1000.0 + 1.0), 500.5);
+        assert_approx_eq!(sim.water_depth_at(3.0 * 1e8), 0.5e8);
+
+        sim.set_schedule(vec![
+            Phase {
+                weather: Weather::Dry,
+                hours: 1.0,
+            },
+            Phase {
+                weather: Weather::Rain,
+                hours: 1.0,
+            },
+        ]);
+
+        assert_approx_eq!(sim.water_depth_at(2.0 * 1e8), 0.5);
+        assert_approx_eq!(sim.water_depth_at(2.0 * 1e8 + 1.0), 0.0);
+    }
+
+    #[test]
+    fn simulation_losses_without_schedule() {
+        let mut sim = Simulation::new();
+        sim.set_loss_rate(0.25);
+        sim.start(&[1.0, 1.0], 2.0);
+
+        sim.forward(2.0);
+
+        assert_slice_approx_eq(sim.get_levels().as_slice(), &[2.5, 2.5]);
+    }
+
+    #[test]
+    fn simulation_schedule_ignores_empty_phases() {
+        let mut sim = Simulation::new();
+        sim.set_schedule(vec![
+            Phase {
+                weather: Weather::Dry,
+                hours: 0.0,
+            },
+            Phase {
+                weather: Weather::Dry,
+                hours: -1.0,
+            },
+        ]);
+
+        assert!(sim.schedule.is_empty());
+    }
+
     pub fn assert_slice_approx_eq(a: &[f64], b: &[f64]) {
         assert_eq!(a.len(), b.len());
         let result = std::panic::catch_unwind(|| {
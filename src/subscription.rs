@@ -0,0 +1,299 @@
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{Envelope, Event};
+
+/// The events and the optional fields of the progress that a connection receives.
+///
+/// Everything is sent until the client subscribes to a subset of it.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Subscription {
+    /// The types of events to receive, or every type if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub events: Option<Vec<EventType>>,
+    /// The optional fields of the progress to receive, or every field if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<ProgressField>>,
+}
+
+/// The types of events sent by the server that can be filtered.
+/// The hellos, the errors and the confirmations of the subscriptions are always sent.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventType {
+    Progress,
+    ProgressDelta,
+    ViewProgress,
+    ComparisonProgress,
+    Lakes,
+    Dropped,
+    SweepProgress,
+    SweepResult,
+    SnapshotData,
+}
+
+/// The fields of the progress events that can be left out
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressField {
+    /// The levels of the segments, and the levels of the alternative in a comparison
+    Levels,
+    /// The differences between the levels of the alternative and the baseline in a comparison
+    Deltas,
+    /// The summary of the differences of a comparison
+    Statistics,
+    /// The reason why the step ended
+    Reason,
+}
+
+impl EventType {
+    fn of(event: &Event) -> Option<EventType> {
+        match event {
+            Event::Progress { .. } => Some(EventType::Progress),
+            Event::ProgressDelta { .. } => Some(EventType::ProgressDelta),
+            Event::ViewProgress { .. } => Some(EventType::ViewProgress),
+            Event::ComparisonProgress { .. } => Some(EventType::ComparisonProgress),
+            Event::Lakes { .. } => Some(EventType::Lakes),
+            Event::Dropped { .. } => Some(EventType::Dropped),
+            Event::SweepProgress { .. } => Some(EventType::SweepProgress),
+            Event::SweepResult { .. } => Some(EventType::SweepResult),
+            Event::SnapshotData { .. } => Some(EventType::SnapshotData),
+            _ => None,
+        }
+    }
+}
+
+impl Subscription {
+    /// The deltas are only meaningful with the keyframes they are applied to, which are sent as full progress
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.includes(EventType::ProgressDelta) || self.includes(EventType::Progress),
+            "progressdelta can not be subscribed without progress, which carries the keyframes"
+        );
+        Ok(())
+    }
+
+    fn includes(&self, event_type: EventType) -> bool {
+        match &self.events {
+            Some(events) => events.contains(&event_type),
+            None => true,
+        }
+    }
+
+    fn has(&self, field: ProgressField) -> bool {
+        match &self.fields {
+            Some(fields) => fields.contains(&field),
+            None => true,
+        }
+    }
+
+    /// Leave out the fields of an event that were not subscribed, or the whole event if its type was not subscribed.
+    ///
+    /// Without the levels, the progress events only keep their time and the rest of their fields.
+    pub fn apply(&self, mut envelope: Envelope) -> Option<Envelope> {
+        match EventType::of(&envelope.event) {
+            Some(event_type) if !self.includes(event_type) => return None,
+            _ => (),
+        }
+
+        let event = &mut envelope.event;
+        if !self.has(ProgressField::Levels) {
+            match event {
+                Event::Progress { levels, .. } => levels.clear(),
+                Event::ProgressDelta {
+                    indices, values, ..
+                } => {
+                    indices.clear();
+                    values.clear();
+                }
+                Event::ViewProgress { buckets, .. } => buckets.clear(),
+                Event::ComparisonProgress {
+                    levels,
+                    alternative_levels,
+                    ..
+                } => {
+                    levels.clear();
+                    alternative_levels.clear();
+                }
+                _ => (),
+            }
+        }
+        if !self.has(ProgressField::Deltas) {
+            if let Event::ComparisonProgress { deltas, .. } = event {
+                deltas.clear();
+            }
+        }
+        if !self.has(ProgressField::Statistics) {
+            if let Event::ComparisonProgress { statistics, .. } = event {
+                *statistics = None;
+            }
+        }
+        if !self.has(ProgressField::Reason) {
+            match event {
+                Event::Progress { reason, .. }
+                | Event::ProgressDelta { reason, .. }
+                | Event::ViewProgress { reason, .. }
+                | Event::ComparisonProgress { reason, .. } => *reason = None,
+                _ => (),
+            }
+        }
+        Some(envelope)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparison::Statistics;
+    use crate::protocol::SimulationId;
+    use crate::simulation::StepReason;
+
+    fn progress() -> Envelope {
+        Envelope::new(
+            SimulationId::default(),
+            Event::Progress {
+                running: true,
+                time: 0.5,
+                levels: vec![1.0, 2.0],
+                reason: Some(StepReason::SinkFilled),
+            },
+        )
+    }
+
+    #[test]
+    fn subscription_sends_everything_by_default() {
+        let subscription = Subscription::default();
+
+        assert_eq!(subscription.apply(progress()), Some(progress()));
+    }
+
+    #[test]
+    fn subscription_filters_the_events() {
+        let subscription = Subscription {
+            events: Some(vec![EventType::Lakes]),
+            fields: None,
+        };
+        let pause = Envelope::new(SimulationId::default(), Event::Pause);
+
+        assert_eq!(subscription.apply(progress()), None);
+        assert_eq!(
+            subscription.apply(pause).map(|envelope| envelope.event),
+            Some(Event::Pause)
+        );
+    }
+
+    #[test]
+    fn subscription_leaves_out_the_fields() {
+        let subscription = Subscription {
+            events: None,
+            fields: Some(vec![ProgressField::Reason]),
+        };
+
+        assert_eq!(
+            subscription
+                .apply(progress())
+                .map(|envelope| envelope.event),
+            Some(Event::Progress {
+                running: true,
+                time: 0.5,
+                levels: vec![],
+                reason: Some(StepReason::SinkFilled),
+            })
+        );
+    }
+
+    #[test]
+    fn subscription_leaves_out_the_fields_of_comparisons() {
+        let subscription = Subscription {
+            events: None,
+            fields: Some(vec![ProgressField::Statistics]),
+        };
+        let statistics = Statistics {
+            min: 0.0,
+            max: 1.0,
+            mean: 0.5,
+            mean_abs: 0.5,
+            changed: 1,
+        };
+        let progress = Envelope::new(
+            SimulationId::default(),
+            Event::ComparisonProgress {
+                running: true,
+                time: 0.5,
+                levels: vec![1.0, 2.0],
+                alternative_levels: vec![1.0, 3.0],
+                deltas: vec![0.0, 1.0],
+                statistics: Some(statistics.clone()),
+                reason: None,
+            },
+        );
+
+        assert_eq!(
+            subscription.apply(progress).map(|envelope| envelope.event),
+            Some(Event::ComparisonProgress {
+                running: true,
+                time: 0.5,
+                levels: vec![],
+                alternative_levels: vec![],
+                deltas: vec![],
+                statistics: Some(statistics),
+                reason: None,
+            })
+        );
+    }
+
+    #[test]
+    fn subscription_leaves_out_the_levels_of_every_progress() {
+        let subscription = Subscription {
+            events: None,
+            fields: Some(vec![]),
+        };
+        let delta = Envelope::new(
+            SimulationId::default(),
+            Event::ProgressDelta {
+                running: true,
+                time: 0.5,
+                indices: vec![1],
+                values: vec![2.0],
+                reason: None,
+            },
+        );
+
+        assert_eq!(
+            subscription.apply(delta).map(|envelope| envelope.event),
+            Some(Event::ProgressDelta {
+                running: true,
+                time: 0.5,
+                indices: vec![],
+                values: vec![],
+                reason: None,
+            })
+        );
+    }
+
+    #[test]
+    fn subscription_validate() {
+        let subscription = |events| Subscription {
+            events: Some(events),
+            fields: None,
+        };
+
+        assert!(Subscription::default().validate().is_ok());
+        assert!(
+            subscription(vec![EventType::Progress, EventType::ProgressDelta])
+                .validate()
+                .is_ok()
+        );
+        assert!(subscription(vec![EventType::ProgressDelta])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn subscription_with_unknown_fields() {
+        let subscription = serde_json::from_str::<Subscription>(r#"{"fields":["levels","flux"]}"#);
+
+        assert!(subscription.is_err());
+    }
+}
@@ -0,0 +1,356 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use anyhow::{ensure, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::simulation::{
+    Phase, Simulation, StepReason, Weather, DELTA_TIME, EVENT_TIME_TOLERANCE, MIN_PHASE_HOURS,
+};
+
+/// The maximum number of combinations of parameters of a sweep
+const MAX_SWEEP_RUNS: usize = 10_000;
+
+/// The maximum number of passes over the segments of the landscape in all the runs of a sweep
+const MAX_SWEEP_WORK: f64 = 1e9;
+
+/// The passes over the segments of a step until the first overflow: the rain and the lakes before and after it
+const PASSES_PER_ADAPTIVE_STEP: f64 = 3.0;
+
+/// The maximum number of levels of all the summaries of a sweep, which keep the peak depths and the final levels of every run
+const MAX_SWEEP_LEVELS: usize = 10_000_000;
+
+/// A grid of parameters to simulate the same landscape with every combination of rain rate and duration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepParameters {
+    pub landscape: Vec<f64>,
+    /// Simulated hours for every run
+    pub hours: f64,
+    /// Depths of water per hour added by the rain
+    pub rain_rates: Vec<f64>,
+    /// Hours of rain at the beginning of every run, which are followed by dry weather (no rain at all with 0)
+    pub durations: Vec<f64>,
+    /// Depth of water per hour lost by outflow, evaporation or infiltration
+    #[serde(default)]
+    pub loss_rate: f64,
+    /// Maximum simulated hours for every step
+    #[serde(default)]
+    pub delta_time: Option<f64>,
+}
+
+/// The outcome of a run of a sweep
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub rain_rate: f64,
+    pub duration: f64,
+    /// The maximum depth of water reached by every segment
+    pub peak_depths: Vec<f64>,
+    /// The time when the first sink got full, if any
+    pub first_overflow: Option<f64>,
+    pub final_levels: Vec<f64>,
+}
+
+impl SweepParameters {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.hours > 0.0, "hours must be positive");
+        ensure!(
+            self.rain_rates
+                .iter()
+                .all(|rain_rate| *rain_rate >= 0.0 && rain_rate.is_finite()),
+            "rain_rates must be finite and can not be negative"
+        );
+        ensure!(
+            self.loss_rate >= 0.0 && self.loss_rate.is_finite(),
+            "loss_rate must be finite and can not be negative"
+        );
+        // The rain and the dry weather are phases of a schedule, so they need to last like the phases of the clients
+        ensure!(
+            self.durations.iter().all(|duration| {
+                let rain = *duration == 0.0 || *duration >= MIN_PHASE_HOURS;
+                let dry = *duration >= self.hours || self.hours - duration >= MIN_PHASE_HOURS;
+                rain && dry && duration.is_finite()
+            }),
+            "durations must be 0 or last at least {} hours, and leave at least as long to the dry weather",
+            MIN_PHASE_HOURS
+        );
+        ensure!(
+            self.runs() <= MAX_SWEEP_RUNS,
+            "the sweep can not have more than {} runs",
+            MAX_SWEEP_RUNS
+        );
+        if let Some(delta_time) = self.delta_time {
+            ensure!(delta_time > 0.0, "delta_time must be positive");
+        }
+        ensure!(
+            self.runs().saturating_mul(self.landscape.len()) * 2 <= MAX_SWEEP_LEVELS,
+            "the results of the sweep can not have more than {} levels",
+            MAX_SWEEP_LEVELS
+        );
+        ensure!(
+            self.work() <= MAX_SWEEP_WORK,
+            "the sweep can not simulate more than {} segments in all its steps",
+            MAX_SWEEP_WORK
+        );
+        Ok(())
+    }
+
+    /// The passes over the segments in all the runs, which bound the time taken by the sweep.
+    /// The steps are adaptive until the first overflow, whose time is found by bisecting a step
+    /// with a rain and the lakes at every attempt, and the rest of steps only need a rain.
+    fn work(&self) -> f64 {
+        let delta_time = self.delta_time.unwrap_or(DELTA_TIME);
+        let steps = (self.hours / delta_time).ceil() + 1.0;
+        let bisections = (delta_time / EVENT_TIME_TOLERANCE).log2().ceil().max(0.0) + 1.0;
+        let passes = steps * PASSES_PER_ADAPTIVE_STEP + bisections * 2.0;
+        self.runs() as f64 * self.landscape.len() as f64 * passes
+    }
+
+    pub fn runs(&self) -> usize {
+        self.rain_rates.len() * self.durations.len()
+    }
+}
+
+/// Run every combination of parameters in parallel across the available cores.
+///
+/// The progress is reported with the number of completed runs and the total number of runs,
+/// and the summaries are returned in the order of the grid (rain rates first).
+/// Nothing is returned when the sweep is cancelled before finishing.
+pub fn run_sweep<F>(
+    parameters: &SweepParameters,
+    cancelled: &AtomicBool,
+    progress: F,
+) -> Option<Vec<RunSummary>>
+where
+    F: Fn(usize, usize) + Sync,
+{
+    let total = parameters.runs();
+    let completed = AtomicUsize::new(0);
+
+    let combinations: Vec<(f64, f64)> = parameters
+        .rain_rates
+        .iter()
+        .flat_map(|rain_rate| {
+            parameters
+                .durations
+                .iter()
+                .map(move |duration| (*rain_rate, *duration))
+        })
+        .collect();
+
+    combinations
+        .into_par_iter()
+        .map(|(rain_rate, duration)| {
+            let summary = run_once(parameters, rain_rate, duration, cancelled)?;
+            progress(completed.fetch_add(1, Ordering::SeqCst) + 1, total);
+            Some(summary)
+        })
+        .collect()
+}
+
+/// The adaptive mode is used to find out the exact time of the first overflow,
+/// and the rest of the run is simulated with fixed steps, as the next events don't need to be found
+fn run_once(
+    parameters: &SweepParameters,
+    rain_rate: f64,
+    duration: f64,
+    cancelled: &AtomicBool,
+) -> Option<RunSummary> {
+    let mut simulation = Simulation::new();
+    simulation.set_rain_rate(rain_rate);
+    simulation.set_loss_rate(parameters.loss_rate);
+    simulation.set_delta_time(parameters.delta_time.unwrap_or(DELTA_TIME));
+    simulation.set_adaptive(true);
+    if duration < parameters.hours {
+        simulation.set_schedule(vec![
+            Phase {
+                weather: Weather::Rain,
+                hours: duration,
+            },
+            Phase {
+                weather: Weather::Dry,
+                hours: parameters.hours - duration,
+            },
+        ]);
+    }
+    simulation.start(parameters.landscape.as_slice(), parameters.hours);
+
+    let ground = simulation.get_levels();
+    let mut peak_depths = vec![0.0; ground.len()];
+    let mut first_overflow = None;
+    while !simulation.is_finished() {
+        if cancelled.load(Ordering::Relaxed) {
+            return None;
+        }
+        simulation.step();
+        if first_overflow.is_none()
+            && matches!(
+                simulation.get_reason(),
+                Some(StepReason::SinkFilled) | Some(StepReason::LakesMerged)
+            )
+        {
+            first_overflow = Some(simulation.get_time());
+            simulation.set_adaptive(false);
+        }
+        for ((peak, level), ground) in peak_depths
+            .iter_mut()
+            .zip(simulation.get_levels())
+            .zip(ground.iter())
+        {
+            *peak = f64::max(*peak, level - ground);
+        }
+    }
+
+    Some(RunSummary {
+        rain_rate,
+        duration,
+        peak_depths,
+        first_overflow,
+        final_levels: simulation.get_levels(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use assert_approx_eq::assert_approx_eq;
+
+    use super::*;
+    use crate::simulation::tests::{assert_slice_approx_eq, assert_slice_approx_eq_with_epsilon};
+
+    fn parameters() -> SweepParameters {
+        SweepParameters {
+            landscape: vec![1.0, 3.0, 5.0],
+            hours: 2.0,
+            rain_rates: vec![1.0, 2.0],
+            durations: vec![0.0, 1.0, 2.0],
+            loss_rate: 0.0,
+            delta_time: Some(0.5),
+        }
+    }
+
+    #[test]
+    fn sweep_runs_every_combination_in_order() {
+        let runs = run_sweep(&parameters(), &AtomicBool::new(false), |_, _| ()).unwrap();
+
+        let combinations: Vec<(f64, f64)> = runs
+            .iter()
+            .map(|run| (run.rain_rate, run.duration))
+            .collect();
+        assert_eq!(
+            combinations,
+            vec![
+                (1.0, 0.0),
+                (1.0, 1.0),
+                (1.0, 2.0),
+                (2.0, 0.0),
+                (2.0, 1.0),
+                (2.0, 2.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn sweep_summaries() {
+        let runs = run_sweep(&parameters(), &AtomicBool::new(false), |_, _| ()).unwrap();
+
+        assert_slice_approx_eq(runs[0].final_levels.as_slice(), &[1.0, 3.0, 5.0]);
+        assert_slice_approx_eq(runs[0].peak_depths.as_slice(), &[0.0, 0.0, 0.0]);
+        assert_eq!(runs[0].first_overflow, None);
+
+        // 3 units of water fill the first segment up to 3 and then spill over the second one
+        assert_slice_approx_eq(runs[1].final_levels.as_slice(), &[3.5, 3.5, 5.0]);
+        assert_slice_approx_eq(runs[1].peak_depths.as_slice(), &[2.5, 0.5, 0.0]);
+        assert_approx_eq!(runs[1].first_overflow.unwrap(), 2.0 / 3.0, 0.01);
+
+        assert_slice_approx_eq_with_epsilon(
+            runs[5].final_levels.as_slice(),
+            &[7.0, 7.0, 7.0],
+            1e-3,
+        );
+        assert_approx_eq!(runs[5].first_overflow.unwrap(), 1.0 / 3.0, 0.01);
+    }
+
+    #[test]
+    fn sweep_progress() {
+        let reported = Mutex::new(vec![]);
+
+        run_sweep(
+            &parameters(),
+            &AtomicBool::new(false),
+            |completed, total| reported.lock().unwrap().push((completed, total)),
+        );
+
+        let mut reported = reported.into_inner().unwrap();
+        reported.sort_unstable();
+        assert_eq!(
+            reported,
+            (1..=6).map(|completed| (completed, 6)).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn sweep_validate() {
+        assert!(parameters().validate().is_ok());
+
+        let mut invalid = parameters();
+        invalid.rain_rates = vec![-1.0];
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = parameters();
+        invalid.durations = vec![0.0; MAX_SWEEP_RUNS + 1];
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = parameters();
+        invalid.hours = 0.0;
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = parameters();
+        invalid.landscape = vec![1.0; 1_000_000];
+        assert!(invalid.validate().is_err());
+
+        let mut invalid = parameters();
+        invalid.hours = 1e9;
+        assert!(invalid.validate().is_err());
+
+        for rain_rate in [f64::INFINITY, f64::NAN] {
+            let mut invalid = parameters();
+            invalid.rain_rates = vec![rain_rate];
+            assert!(invalid.validate().is_err());
+        }
+
+        for loss_rate in [-1.0, f64::INFINITY, f64::NAN] {
+            let mut invalid = parameters();
+            invalid.loss_rate = loss_rate;
+            assert!(invalid.validate().is_err());
+        }
+
+        for duration in [-1.0, 0.001, 1.999, f64::INFINITY, f64::NAN] {
+            let mut invalid = parameters();
+            invalid.durations = vec![duration];
+            assert!(invalid.validate().is_err());
+        }
+
+        let mut valid = parameters();
+        valid.durations = vec![0.01, 1.99, 2.0, 3.0];
+        assert!(valid.validate().is_ok());
+    }
+
+    #[test]
+    fn sweep_work() {
+        // 6 runs of 3 segments with 5 adaptive steps and 10 bisections of a step of half an hour
+        assert_approx_eq!(parameters().work(), 6.0 * 3.0 * (5.0 * 3.0 + 10.0 * 2.0));
+    }
+
+    #[test]
+    fn sweep_cancelled() {
+        let progress = AtomicUsize::new(0);
+
+        let runs = run_sweep(&parameters(), &AtomicBool::new(true), |_, _| {
+            progress.fetch_add(1, Ordering::SeqCst);
+        });
+
+        assert_eq!(runs, None);
+        assert_eq!(progress.load(Ordering::SeqCst), 0);
+    }
+}
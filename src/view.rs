@@ -0,0 +1,118 @@
+use anyhow::{ensure, Result};
+use serde::{Deserialize, Serialize};
+
+/// A window of segments of the landscape, which is reduced to a number of buckets to be drawn
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct View {
+    /// The first segment of the window
+    pub start: usize,
+    /// The segment after the last one of the window
+    pub end: usize,
+    /// The maximum number of buckets, as there are never more buckets than segments
+    pub buckets: usize,
+}
+
+/// The levels of the segments of a bucket
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+impl View {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.start < self.end, "start must be lower than end");
+        ensure!(self.buckets > 0, "buckets must be positive");
+        Ok(())
+    }
+
+    /// The range of segments of the window that are part of the landscape
+    pub fn range(&self, segments: usize) -> (usize, usize) {
+        (self.start.min(segments), self.end.min(segments))
+    }
+
+    /// Split the window into buckets of consecutive segments with (almost) the same size
+    pub fn buckets(&self, levels: &[f64]) -> Vec<Bucket> {
+        let (start, end) = self.range(levels.len());
+        let width = end - start;
+        let buckets = self.buckets.min(width);
+
+        (0..buckets)
+            .map(|bucket| {
+                let first = start + bucket * width / buckets;
+                let last = start + (bucket + 1) * width / buckets;
+                let levels = &levels[first..last];
+                Bucket {
+                    min: levels.iter().cloned().fold(f64::INFINITY, f64::min),
+                    max: levels.iter().cloned().fold(f64::NEG_INFINITY, f64::max),
+                    mean: levels.iter().sum::<f64>() / levels.len() as f64,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn view(start: usize, end: usize, buckets: usize) -> View {
+        View {
+            start,
+            end,
+            buckets,
+        }
+    }
+
+    #[test]
+    fn view_buckets() {
+        let levels = [1.0, 3.0, 2.0, 4.0, 6.0, 5.0];
+
+        assert_eq!(
+            view(0, 6, 2).buckets(&levels),
+            vec![
+                Bucket {
+                    min: 1.0,
+                    max: 3.0,
+                    mean: 2.0
+                },
+                Bucket {
+                    min: 4.0,
+                    max: 6.0,
+                    mean: 5.0
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn view_buckets_of_a_window() {
+        let levels = [1.0, 3.0, 2.0, 4.0, 6.0, 5.0];
+
+        let buckets = view(1, 4, 2).buckets(&levels);
+
+        assert_eq!(buckets.len(), 2);
+        assert_eq!((buckets[0].min, buckets[0].max), (3.0, 3.0));
+        assert_eq!((buckets[1].min, buckets[1].max), (2.0, 4.0));
+    }
+
+    #[test]
+    fn view_with_more_buckets_than_segments() {
+        let levels = [1.0, 3.0, 2.0];
+
+        let buckets = view(1, 10, 100).buckets(&levels);
+
+        assert_eq!(view(1, 10, 100).range(levels.len()), (1, 3));
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[1].mean, 2.0);
+        assert!(view(5, 10, 2).buckets(&levels).is_empty());
+    }
+
+    #[test]
+    fn view_validate() {
+        assert!(view(0, 1, 1).validate().is_ok());
+        assert!(view(1, 1, 1).validate().is_err());
+        assert!(view(0, 1, 0).validate().is_err());
+    }
+}
@@ -1,16 +1,19 @@
+use serde::{Deserialize, Serialize};
+
+use crate::numeric::{Height, Volume};
+
 type SegmentLevel = u32;
 
+/// Water levels below this threshold are considered rounding errors rather than water
+const WATER_EPSILON: f64 = 1e-6;
+
 /// This allows to identify the different types of areas that can be derived from the analysis of a level
 #[derive(Debug, PartialEq)]
-enum Area {
+enum Area<H> {
     /// This represents the infinite walls in the extremes
     Boundary,
     /// This represents a depression in the terrain
-    Sink {
-        start: usize,
-        end: usize,
-        bottom: SegmentLevel,
-    },
+    Sink { start: usize, end: usize, bottom: H },
     /// This represents a plain surface that will spill water to the adjacent sinks
     Plain {
         start: usize,
@@ -19,12 +22,12 @@ enum Area {
     },
 }
 
-impl Area {
-    pub fn width(&self) -> f64 {
+impl<H> Area<H> {
+    pub fn width<V: Volume>(&self) -> V {
         match self {
-            Area::Boundary => 0.0,
-            Area::Plain { length, sinks, .. } => *length as f64 / *sinks as f64,
-            Area::Sink { start, end, .. } => (*end - *start + 1) as f64,
+            Area::Boundary => V::ZERO,
+            Area::Plain { length, sinks, .. } => V::from_usize(*length) / V::from_usize(*sinks),
+            Area::Sink { start, end, .. } => V::from_usize(*end - *start + 1),
         }
     }
 }
@@ -35,34 +38,34 @@ impl Area {
 /// Sinks can have children representing the water contained in the underlying sinks (under the bottom level).
 /// Leaf Sinks represent water above a plain of terrain that does not connect with any other underlying sinks.
 ///
-#[derive(Debug, PartialEq)]
-struct Sink {
-    weight: f64,
+#[derive(Debug, Clone, PartialEq)]
+struct Sink<H, V> {
+    weight: V,
     start: usize,
     end: usize,
-    top: SegmentLevel,
-    bottom: SegmentLevel,
-    capacity: f64,
-    total_capacity: f64,
-    water: f64,
-    children: Vec<Sink>,
+    top: H,
+    bottom: H,
+    capacity: V,
+    total_capacity: V,
+    water: V,
+    children: Vec<Sink<H, V>>,
 }
 
-impl Sink {
+impl<H: Height, V: Volume> Sink<H, V> {
     pub fn new(
-        weight: f64,
+        weight: V,
         start: usize,
         end: usize,
-        top: SegmentLevel,
-        bottom: SegmentLevel,
-        children: Vec<Sink>,
-    ) -> Sink {
-        let width = (end - start + 1) as f64;
-        let capacity = width * (top - bottom) as f64;
+        top: H,
+        bottom: H,
+        children: Vec<Sink<H, V>>,
+    ) -> Sink<H, V> {
+        let width = V::from_usize(end - start + 1);
+        let capacity = width * (V::from_height(top) - V::from_height(bottom));
         let children_capacity = children
             .iter()
             .map(|child| child.total_capacity)
-            .fold(0.0, |accum, child_total_capacity| {
+            .fold(V::ZERO, |accum, child_total_capacity| {
                 accum + child_total_capacity
             });
         let total_capacity = capacity + children_capacity;
@@ -75,7 +78,7 @@ impl Sink {
             bottom,
             capacity,
             total_capacity,
-            water: 0.0,
+            water: V::ZERO,
             children,
         }
     }
@@ -86,40 +89,84 @@ impl Sink {
     }
 
     #[inline]
-    pub fn width(&self) -> f64 {
-        (self.end - self.start + 1) as f64
+    pub fn width(&self) -> V {
+        V::from_usize(self.end - self.start + 1)
     }
 
     #[inline]
-    pub fn total_water(&self) -> f64 {
+    pub fn total_water(&self) -> V {
         self.water
             + self
                 .children
                 .iter()
                 .map(|child| child.total_water())
-                .fold(0.0, |accum, water| accum + water)
+                .fold(V::ZERO, |accum, water| accum + water)
     }
 }
 
+/// The side of a sink rim
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// A SpillPoint represents the segment of the rim of a sink over which the water will spill once the sink is full.
+///
+/// The root sink is bounded by the infinite walls in the extremes, so it has no spill point.
+/// When both sides of the rim are at the same elevation, the left one is reported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SpillPoint<H = SegmentLevel> {
+    pub sink: usize,
+    pub segment: usize,
+    pub side: Side,
+    pub elevation: H,
+}
+
+/// A Lake represents a contiguous body of water with a flat surface in the segments [start, end].
+///
+/// Every lake is contained by a sink of the hierarchy, which is identified by its position
+/// in a pre-order traversal of the hierarchy (the root sink being the 0).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Lake<H = SegmentLevel> {
+    pub sink: usize,
+    pub start: usize,
+    pub end: usize,
+    pub surface: f64,
+    pub volume: f64,
+    pub max_depth: f64,
+    pub spill: Option<SpillPoint<H>>,
+}
+
 /// This simulates the flow of the water coming from the rain through a landscape
-#[derive(Debug)]
-pub struct WaterFlow {
-    landscape: Vec<SegmentLevel>,
-    water: Vec<f64>,
-    root_sink: Option<Sink>,
+///
+/// The levels of the terrain are represented with `u32` and the volumes of water with `f64` by default,
+/// but any other [`Height`] and [`Volume`] can be used.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WaterFlow<H = SegmentLevel, V = f64> {
+    landscape: Vec<H>,
+    water: Vec<V>,
+    root_sink: Option<Sink<H, V>>,
 }
 
-impl WaterFlow {
+impl<H: Height, V: Volume> WaterFlow<H, V> {
     /// It builds the hierarchy of sinks for a landscape and returns a WaterFlow instance
-    pub fn new(landscape: Vec<SegmentLevel>) -> WaterFlow {
-        let water = vec![0.0; landscape.len()];
+    ///
+    /// It panics if any height of the landscape is not finite, as the hierarchy can not be built without a total order.
+    pub fn new(landscape: Vec<H>) -> WaterFlow<H, V> {
+        assert!(
+            landscape.iter().all(|height| height.is_finite()),
+            "the heights of the landscape must be finite"
+        );
+        let water = vec![V::ZERO; landscape.len()];
 
         let root_sink = (!landscape.is_empty()).then(|| {
             let end = landscape.len() - 1;
-            let bottom = landscape.iter().cloned().max().unwrap_or(0);
+            let bottom = landscape.iter().cloned().fold(H::MIN, H::highest);
             let children = Self::build_sinks_hierarchy(landscape.as_slice(), 0, end, bottom);
 
-            Sink::new(1.0, 0, end, SegmentLevel::MAX, bottom, children)
+            Sink::new(V::ONE, 0, end, H::MAX, bottom, children)
         });
 
         WaterFlow {
@@ -131,18 +178,18 @@ impl WaterFlow {
 
     /// It build the hierarchy of sinks for a region of the landscape under a certain segment level
     fn build_sinks_hierarchy(
-        landscape: &[SegmentLevel],
+        landscape: &[H],
         start: usize,
         end: usize,
-        level: SegmentLevel,
-    ) -> Vec<Sink> {
-        let mut sinks = Vec::<Sink>::with_capacity((end - start + 3) / 2);
+        level: H,
+    ) -> Vec<Sink<H, V>> {
+        let mut sinks = Vec::<Sink<H, V>>::with_capacity((end - start + 3) / 2);
 
         let areas = Self::scan_areas(landscape, start, end, level);
 
-        let total_width = (end - start + 1) as f64;
+        let total_width = V::from_usize(end - start + 1);
 
-        let mut total_weight = 0.0;
+        let mut total_weight = V::ZERO;
         for index in 1..areas.len() - 1 {
             if let Area::Sink { start, end, bottom } = &areas[index] {
                 let weight = Self::calculate_sink_weight(areas.as_slice(), index, total_width);
@@ -154,8 +201,8 @@ impl WaterFlow {
         }
 
         // In case there are floating point errors that we need to compensate for
-        if !sinks.is_empty() && total_weight < 1.0 {
-            sinks[0].weight += 1.0 - total_weight;
+        if !sinks.is_empty() && total_weight < V::ONE {
+            sinks[0].weight += V::ONE - total_weight;
         }
 
         sinks
@@ -163,13 +210,8 @@ impl WaterFlow {
 
     /// Scan the areas of a landscape for a given region and up to a certain level
     /// The areas will include information about boundaries, plains and sinks.
-    fn scan_areas(
-        landscape: &[SegmentLevel],
-        start: usize,
-        end: usize,
-        level: SegmentLevel,
-    ) -> Vec<Area> {
-        let mut areas = Vec::<Area>::with_capacity(landscape.len());
+    fn scan_areas(landscape: &[H], start: usize, end: usize, level: H) -> Vec<Area<H>> {
+        let mut areas = Vec::<Area<H>>::with_capacity(landscape.len());
         areas.push(Area::Boundary);
 
         let mut index = start;
@@ -187,12 +229,7 @@ impl WaterFlow {
     }
 
     /// Scan information about a plain (contiguous segments with the same level)
-    fn scan_plain(
-        landscape: &[SegmentLevel],
-        index: &mut usize,
-        end: usize,
-        level: SegmentLevel,
-    ) -> Area {
+    fn scan_plain(landscape: &[H], index: &mut usize, end: usize, level: H) -> Area<H> {
         let start = *index;
         while *index <= end && landscape[*index] == level {
             *index += 1;
@@ -211,16 +248,11 @@ impl WaterFlow {
     }
 
     /// Scan information about a sink (a depression in the landscape)
-    fn scan_sink(
-        landscape: &[SegmentLevel],
-        index: &mut usize,
-        end: usize,
-        level: SegmentLevel,
-    ) -> Area {
+    fn scan_sink(landscape: &[H], index: &mut usize, end: usize, level: H) -> Area<H> {
         let start = *index;
-        let mut bottom = 0;
+        let mut bottom = H::MIN;
         while *index <= end && landscape[*index] < level {
-            bottom = bottom.max(landscape[*index]);
+            bottom = H::highest(bottom, landscape[*index]);
             *index += 1;
         }
 
@@ -236,7 +268,7 @@ impl WaterFlow {
     }
 
     /// Push a new scanned area to a list of areas
-    fn push_area(areas: &mut Vec<Area>, area: Area) {
+    fn push_area(areas: &mut Vec<Area<H>>, area: Area<H>) {
         assert!(!areas.is_empty());
         let last_area = areas.last_mut().unwrap();
 
@@ -264,10 +296,10 @@ impl WaterFlow {
 
     /// Calculate the proportion of water that will flow through the sink from the rain respect to the total landscape width,
     /// which comes from the sink region itself plus half the region of the contiguous plains.
-    fn calculate_sink_weight(areas: &[Area], index: usize, total_width: f64) -> f64 {
-        let left_width = areas[index - 1].width();
-        let right_width = areas[index + 1].width();
-        let width = areas[index].width() + left_width + right_width;
+    fn calculate_sink_weight(areas: &[Area<H>], index: usize, total_width: V) -> V {
+        let left_width = areas[index - 1].width::<V>();
+        let right_width = areas[index + 1].width::<V>();
+        let width = areas[index].width::<V>() + left_width + right_width;
         width / total_width
     }
 
@@ -276,18 +308,167 @@ impl WaterFlow {
         self.landscape
             .iter()
             .zip(self.water.iter())
-            .map(|(segment_level, water_level)| *segment_level as f64 + *water_level)
+            .map(|(segment_level, water_level)| segment_level.to_f64() + water_level.to_f64())
             .collect()
     }
 
+    /// Return the number of sinks in the hierarchy that can not hold more water (the root sink is never full)
+    pub fn full_sinks(&self) -> usize {
+        self.root_sink
+            .as_ref()
+            .map(|sink| {
+                sink.children
+                    .iter()
+                    .map(|child| self.count_full_sinks(child))
+                    .sum()
+            })
+            .unwrap_or(0)
+    }
+
+    /// The water of the sinks is flooded into the segments after every rain, so it is calculated from them.
+    /// The water above the top of a sink belongs to its parent, which only receives water once the sink is full.
+    fn count_full_sinks(&self, sink: &Sink<H, V>) -> usize {
+        let water = self.water[sink.start..=sink.end]
+            .iter()
+            .fold(V::ZERO, |accum, water| accum + *water);
+        let full = water + V::from_f64(WATER_EPSILON) >= sink.total_capacity;
+        let children: usize = sink
+            .children
+            .iter()
+            .map(|child| self.count_full_sinks(child))
+            .sum();
+        children + full as usize
+    }
+
+    /// Return the spill points of all the sinks in the hierarchy, sorted by the sink identifier
+    pub fn spill_points(&self) -> Vec<SpillPoint<H>> {
+        let mut spill_points = Vec::new();
+        if let Some(sink) = self.root_sink.as_ref() {
+            let mut next_id = 1;
+            for child in sink.children.iter() {
+                self.collect_spill_points(child, &mut next_id, &mut spill_points);
+            }
+        }
+        spill_points
+    }
+
+    /// Find the spill point of a sink and its children.
+    /// The rim of a sink is given by the segments just outside of its region, and the water will spill over the lower one.
+    fn collect_spill_points(
+        &self,
+        sink: &Sink<H, V>,
+        next_id: &mut usize,
+        spill_points: &mut Vec<SpillPoint<H>>,
+    ) {
+        let id = *next_id;
+        *next_id += 1;
+
+        let left = sink
+            .start
+            .checked_sub(1)
+            .map(|segment| (segment, Side::Left));
+        let right = Some(sink.end + 1)
+            .filter(|segment| *segment < self.landscape.len())
+            .map(|segment| (segment, Side::Right));
+
+        let spill_point = left
+            .into_iter()
+            .chain(right)
+            .map(|(segment, side)| SpillPoint {
+                sink: id,
+                segment,
+                side,
+                elevation: self.landscape[segment],
+            })
+            .fold(
+                None,
+                |lowest: Option<SpillPoint<H>>, spill_point| match lowest {
+                    Some(lowest) if lowest.elevation <= spill_point.elevation => Some(lowest),
+                    _ => Some(spill_point),
+                },
+            );
+        spill_points.extend(spill_point);
+
+        for child in sink.children.iter() {
+            self.collect_spill_points(child, next_id, spill_points);
+        }
+    }
+
+    /// Return the lakes formed by the water after the last simulation of rain
+    pub fn lakes(&self) -> Vec<Lake<H>> {
+        let mut lakes = Vec::new();
+        if let Some(sink) = self.root_sink.as_ref() {
+            let spill_points = self.spill_points();
+            let mut next_id = 0;
+            self.collect_lakes(sink, false, &spill_points, &mut next_id, &mut lakes);
+        }
+        lakes
+    }
+
+    /// Traverse the hierarchy of sinks looking for the upper ones whose water is above their bottom level.
+    /// The water of those sinks covers all their segments, so they will contain a single lake each.
+    fn collect_lakes(
+        &self,
+        sink: &Sink<H, V>,
+        submerged: bool,
+        spill_points: &[SpillPoint<H>],
+        next_id: &mut usize,
+        lakes: &mut Vec<Lake<H>>,
+    ) {
+        let id = *next_id;
+        *next_id += 1;
+
+        let mut submerged = submerged;
+        if !submerged {
+            let range = sink.start..=sink.end;
+            let (surface, volume, max_depth, min_level) = self.landscape[range.clone()]
+                .iter()
+                .zip(self.water[range].iter())
+                .fold(
+                    (0.0, 0.0, 0.0, f64::MAX),
+                    |(surface, volume, max_depth, min_level), (terrain, water)| {
+                        let water = water.to_f64();
+                        let level = terrain.to_f64() + water;
+                        (
+                            surface + level,
+                            volume + water,
+                            f64::max(max_depth, water),
+                            f64::min(min_level, level),
+                        )
+                    },
+                );
+
+            if min_level > sink.bottom.to_f64() + WATER_EPSILON {
+                submerged = true;
+                lakes.push(Lake {
+                    sink: id,
+                    start: sink.start,
+                    end: sink.end,
+                    surface: surface / sink.width().to_f64(),
+                    volume,
+                    max_depth,
+                    spill: spill_points
+                        .binary_search_by_key(&id, |spill_point| spill_point.sink)
+                        .ok()
+                        .map(|index| spill_points[index].clone()),
+                });
+            }
+        }
+
+        // The children are traversed anyway to keep the identifiers of the sinks consistent
+        for child in sink.children.iter() {
+            self.collect_lakes(child, submerged, spill_points, next_id, lakes);
+        }
+    }
+
     /// Simulate the flow of water for some hours of rain
     /// This operation is not accumulative and will update the internal state according to this simulation.
     pub fn rain(&mut self, hours: f64) {
         if let Some(sink) = self.root_sink.as_mut() {
-            let total_water = self.landscape.len() as f64 * hours;
+            let total_water = V::from_usize(self.landscape.len()) * V::from_f64(hours);
             Self::fill_sink_with_water(self.landscape.as_slice(), sink, total_water);
 
-            self.water.fill(0.0);
+            self.water.fill(V::ZERO);
             Self::flood_water_to_landscape(
                 self.landscape.as_slice(),
                 self.water.as_mut_slice(),
@@ -297,9 +478,9 @@ impl WaterFlow {
     }
 
     /// Calculate the flow of certain amount of water through the sinks hierarchy
-    fn fill_sink_with_water(landscape: &[SegmentLevel], sink: &mut Sink, amount: f64) -> f64 {
+    fn fill_sink_with_water(landscape: &[H], sink: &mut Sink<H, V>, amount: V) -> V {
         let num_children = sink.children.len();
-        let mut excess = vec![0.0; num_children];
+        let mut excess = vec![V::ZERO; num_children];
 
         let (mut children_amount, total_excess) =
             Self::fill_downstream_sinks_with_water(landscape, sink, amount, excess.as_mut_slice());
@@ -308,7 +489,7 @@ impl WaterFlow {
             Self::spill_excess_water_through_sinks(landscape, &mut excess, total_excess, sink);
 
         let remaining = amount - children_amount;
-        let sink_amount = f64::min(sink.capacity - sink.water, remaining);
+        let sink_amount = V::min(sink.capacity - sink.water, remaining);
         sink.water += sink_amount;
 
         children_amount + sink_amount
@@ -316,26 +497,26 @@ impl WaterFlow {
 
     /// Push water downstream through the hierarchy of sinks
     fn fill_downstream_sinks_with_water(
-        landscape: &[SegmentLevel],
-        sink: &mut Sink,
-        amount: f64,
-        excess: &mut [f64],
-    ) -> (f64, f64) {
-        let mut total_filled = 0.0;
-        let mut total_excess = 0.0;
+        landscape: &[H],
+        sink: &mut Sink<H, V>,
+        amount: V,
+        excess: &mut [V],
+    ) -> (V, V) {
+        let mut total_filled = V::ZERO;
+        let mut total_excess = V::ZERO;
 
         // We need to compensate for possible floating point errors
         let total_quota = sink
             .children
             .iter()
             .map(|child| amount * child.weight)
-            .fold(0.0, |acc, quota| acc + quota);
+            .fold(V::ZERO, |acc, quota| acc + quota);
         let mut quota_error = amount - total_quota;
 
         for (child, sink_excess) in sink.children.iter_mut().zip(excess.iter_mut()) {
             if !child.is_full() {
                 let quota = amount * child.weight + quota_error;
-                quota_error = 0.0;
+                quota_error = V::ZERO;
                 let filled = Self::fill_sink_with_water(landscape, child, quota);
                 *sink_excess = quota - filled;
                 total_excess += *sink_excess;
@@ -349,19 +530,19 @@ impl WaterFlow {
     /// Try to spill excess water from the downstream sinks into contiguous sinks,
     /// and finally add the remaining excess to the parent sink.
     fn spill_excess_water_through_sinks(
-        landscape: &[SegmentLevel],
-        excess: &mut [f64],
-        total_excess: f64,
-        sink: &mut Sink,
-    ) -> f64 {
-        let mut total_spilled = 0.0;
-        if total_excess > 0.0 && sink.children.len() > 1 {
+        landscape: &[H],
+        excess: &mut [V],
+        total_excess: V,
+        sink: &mut Sink<H, V>,
+    ) -> V {
+        let mut total_spilled = V::ZERO;
+        if total_excess > V::ZERO && sink.children.len() > 1 {
             for (index, sink_excess) in excess.iter_mut().enumerate() {
-                if *sink_excess > 0.0 {
+                if *sink_excess > V::ZERO {
                     let children = sink.children.as_slice();
                     let left_capacity = Self::find_spill_capacity(children, index, -1);
                     let right_capacity = Self::find_spill_capacity(children, index, 1);
-                    if left_capacity + right_capacity > 0.0 {
+                    if left_capacity + right_capacity > V::ZERO {
                         let (left_water, right_water) =
                             Self::spilled_amount(*sink_excess, left_capacity, right_capacity);
                         let children = sink.children.as_mut_slice();
@@ -381,9 +562,9 @@ impl WaterFlow {
 
     /// Before we can spill excess water to both sides of a sink,
     /// we need to know the total capacity available in the contiguous sinks
-    fn find_spill_capacity(sinks: &[Sink], index: usize, direction: isize) -> f64 {
+    fn find_spill_capacity(sinks: &[Sink<H, V>], index: usize, direction: isize) -> V {
         let mut index = index as isize;
-        let mut capacity = 0.0;
+        let mut capacity = V::ZERO;
         index += direction;
         while index >= 0 && (index as usize) < sinks.len() {
             let sink = &sinks[index as usize];
@@ -396,11 +577,23 @@ impl WaterFlow {
     /// To calculate the right amount of water that will spill in each direction
     /// we calculate proportions from the available capacities
     /// and treat them as a 2D vector that can be normalized.
-    fn spilled_amount(sink_excess: f64, left_capacity: f64, right_capacity: f64) -> (f64, f64) {
-        let left_proportion = f64::min(sink_excess, left_capacity) / sink_excess;
-        let right_proportion = f64::min(sink_excess, right_capacity) / sink_excess;
+    ///
+    /// The square root is only needed when the water spills to both sides.
+    fn spilled_amount(sink_excess: V, left_capacity: V, right_capacity: V) -> (V, V) {
+        let left_proportion = V::min(sink_excess, left_capacity) / sink_excess;
+        let right_proportion = V::min(sink_excess, right_capacity) / sink_excess;
+        if left_proportion == V::ZERO || right_proportion == V::ZERO {
+            let side = |proportion| {
+                if proportion == V::ZERO {
+                    V::ZERO
+                } else {
+                    sink_excess
+                }
+            };
+            return (side(left_proportion), side(right_proportion));
+        }
         let modulo =
-            f64::sqrt(left_proportion * left_proportion + right_proportion * right_proportion);
+            V::sqrt(left_proportion * left_proportion + right_proportion * right_proportion);
         let left_water = sink_excess * left_proportion / modulo;
         let right_water = sink_excess * right_proportion / modulo;
         (left_water, right_water)
@@ -408,18 +601,18 @@ impl WaterFlow {
 
     /// Spill a certain amount of water towards the contiguous sinks in a certain direction
     fn spill_water(
-        landscape: &[SegmentLevel],
-        sinks: &mut [Sink],
+        landscape: &[H],
+        sinks: &mut [Sink<H, V>],
         index: isize,
         direction: isize,
-        mut amount: f64,
-    ) -> f64 {
-        let mut total_spilled = 0.0;
+        mut amount: V,
+    ) -> V {
+        let mut total_spilled = V::ZERO;
         let mut index = index as isize;
         index += direction;
-        while amount > 0.0 && index >= 0 && (index as usize) < sinks.len() {
+        while amount > V::ZERO && index >= 0 && (index as usize) < sinks.len() {
             let sink = &mut sinks[index as usize];
-            if sink.total_capacity - sink.total_water() > 0.0 {
+            if sink.total_capacity - sink.total_water() > V::ZERO {
                 let spill_amount = if sink.children.is_empty() {
                     Self::fill_sink_with_water(landscape, sink, amount)
                 } else {
@@ -443,18 +636,18 @@ impl WaterFlow {
 
     /// Once all the sinks have been filled with water we need to flood that water into the segments of the landscape.
     /// We do it recursively from the leafs towards the upper sinks.
-    fn flood_water_to_landscape(terrain: &[SegmentLevel], water: &mut [f64], sink: &mut Sink) {
+    fn flood_water_to_landscape(terrain: &[H], water: &mut [V], sink: &mut Sink<H, V>) {
         for child in sink.children.iter_mut() {
             Self::flood_water_to_landscape(terrain, water, child);
         }
 
-        if sink.water > 0.0 {
+        if sink.water > V::ZERO {
             let segment_amount = sink.water / sink.width();
 
             let mut remaining = sink.water;
-            sink.water = 0.0;
+            sink.water = V::ZERO;
 
-            let mut lower_level = f64::MAX;
+            let mut lower_level = V::MAX;
             let mut lower_offset = 0usize;
             let range = sink.start..=sink.end;
             let segments = water[range.clone()]
@@ -464,7 +657,7 @@ impl WaterFlow {
             for (offset, (water_level, terrain_level)) in segments {
                 *water_level += segment_amount;
                 remaining -= segment_amount;
-                let level = *terrain_level as f64 + *water_level;
+                let level = V::from_height(*terrain_level) + *water_level;
                 if level < lower_level {
                     lower_level = level;
                     lower_offset = offset;
@@ -474,7 +667,7 @@ impl WaterFlow {
             // Check for f64 rounding errors and flood the remaining water
             // into the segment with the lower level. That's important to
             // conserve the total amount of water constant.
-            if remaining > 0.0 {
+            if remaining > V::ZERO {
                 water[sink.start + lower_offset] += remaining;
             }
         }
@@ -488,17 +681,18 @@ mod tests {
     use rand::Rng;
 
     use super::*;
+    use crate::numeric::tests::Rational;
     use crate::simulation::tests::assert_slice_approx_eq_with_epsilon;
 
     #[test]
     fn water_flow_new_with_empty_terrain() {
-        let water_flow = WaterFlow::new(vec![]);
+        let water_flow: WaterFlow = WaterFlow::new(vec![]);
         assert!(water_flow.total_levels().is_empty())
     }
 
     #[test]
     fn water_flow_new_initializes_landscape_and_water_levels() {
-        let water_flow = WaterFlow::new(vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
+        let water_flow: WaterFlow = WaterFlow::new(vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
 
         assert_eq!(water_flow.landscape, vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
         assert!(water_flow.water.iter().all(|value| *value == 0.0))
@@ -506,7 +700,7 @@ mod tests {
 
     #[test]
     fn water_flow_new_builds_the_hierarchy_of_sinks() {
-        let water_flow = WaterFlow::new(vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
+        let water_flow: WaterFlow = WaterFlow::new(vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
 
         assert_eq!(
             water_flow.root_sink,
@@ -603,7 +797,7 @@ mod tests {
 
     #[test]
     fn water_flow_rain_fill_simple_hierarchy() {
-        let mut water_flow = WaterFlow::new(vec![6, 4, 5, 9]);
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![6, 4, 5, 9]);
 
         water_flow.rain(4.0);
 
@@ -616,7 +810,7 @@ mod tests {
 
     #[test]
     fn water_flow_rain_fill_and_spill_binary_hierarchy() {
-        let mut water_flow = WaterFlow::new(vec![2, 6, 5, 9]);
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![2, 6, 5, 9]);
 
         water_flow.rain(2.0);
 
@@ -629,7 +823,7 @@ mod tests {
 
     #[test]
     fn water_flow_rain_spill_equally_to_the_sides() {
-        let mut water_flow = WaterFlow::new(vec![1, 4, 4, 3, 4, 4, 1]);
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![1, 4, 4, 3, 4, 4, 1]);
 
         water_flow.rain(1.0);
 
@@ -642,7 +836,7 @@ mod tests {
 
     #[test]
     fn water_flow_rain_spill_with_recursion() {
-        let mut water_flow = WaterFlow::new(vec![4, 1, 4, 6, 5]);
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![4, 1, 4, 6, 5]);
 
         water_flow.rain(2.0);
 
@@ -655,7 +849,7 @@ mod tests {
 
     #[test]
     fn water_flow_rain_spill_with_recursion_and_fill_up() {
-        let mut water_flow = WaterFlow::new(vec![4, 7, 5, 8, 6, 9, 7]);
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![4, 7, 5, 8, 6, 9, 7]);
 
         water_flow.rain(2.0);
 
@@ -666,6 +860,200 @@ mod tests {
         );
     }
 
+    #[test]
+    fn water_flow_spill_points_of_the_hierarchy_of_sinks() {
+        let water_flow: WaterFlow = WaterFlow::new(vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7]);
+
+        let spill_points = water_flow
+            .spill_points()
+            .iter()
+            .map(|point| (point.sink, point.segment, point.side, point.elevation))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            spill_points,
+            vec![
+                (1, 3, Side::Right, 9),
+                (2, 0, Side::Left, 6),
+                (3, 2, Side::Right, 5),
+                (4, 4, Side::Left, 9),
+                (5, 6, Side::Right, 6),
+                (6, 6, Side::Left, 6),
+                (7, 8, Side::Left, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn water_flow_full_sinks() {
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![1, 3, 5]);
+        assert_eq!(water_flow.full_sinks(), 0);
+
+        // The sink of the first segment holds 2 units of water, and its parent 4 more
+        water_flow.rain(0.5);
+        assert_eq!(water_flow.full_sinks(), 0);
+
+        water_flow.rain(1.0);
+        assert_eq!(water_flow.full_sinks(), 1);
+
+        water_flow.rain(2.0);
+        assert_eq!(water_flow.full_sinks(), 2);
+
+        let water_flow: WaterFlow = WaterFlow::new(vec![]);
+        assert_eq!(water_flow.full_sinks(), 0);
+    }
+
+    #[test]
+    fn water_flow_spill_points_with_empty_terrain() {
+        let water_flow: WaterFlow = WaterFlow::new(vec![]);
+
+        assert!(water_flow.spill_points().is_empty());
+    }
+
+    #[test]
+    fn water_flow_lakes_without_rain() {
+        let water_flow: WaterFlow = WaterFlow::new(vec![6, 4, 5, 9]);
+
+        assert!(water_flow.lakes().is_empty());
+    }
+
+    #[test]
+    fn water_flow_lakes_merged_into_the_upper_sink() {
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![2, 6, 5, 9]);
+
+        water_flow.rain(2.0);
+
+        let lakes = water_flow.lakes();
+        assert_eq!(lakes.len(), 1);
+        assert_eq!(lakes[0].sink, 1);
+        assert_eq!((lakes[0].start, lakes[0].end), (0, 2));
+        assert_approx_eq!(lakes[0].surface, 7.0, 0.1);
+        assert_approx_eq!(lakes[0].volume, 8.0, 0.1);
+        assert_approx_eq!(lakes[0].max_depth, 5.0, 0.1);
+        assert_eq!(
+            lakes[0].spill,
+            Some(SpillPoint {
+                sink: 1,
+                segment: 3,
+                side: Side::Right,
+                elevation: 9
+            })
+        );
+    }
+
+    #[test]
+    fn water_flow_lakes_covering_the_whole_landscape() {
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![2, 3]);
+
+        water_flow.rain(4.0);
+
+        let lakes = water_flow.lakes();
+        assert_eq!(lakes.len(), 1);
+        assert_eq!((lakes[0].sink, lakes[0].start, lakes[0].end), (0, 0, 1));
+        assert_eq!(lakes[0].spill, None);
+    }
+
+    #[test]
+    fn water_flow_lakes_in_separated_sinks() {
+        let mut water_flow: WaterFlow = WaterFlow::new(vec![1, 4, 4, 3, 4, 4, 1]);
+
+        water_flow.rain(1.0);
+
+        let lakes = water_flow.lakes();
+        let summary = lakes
+            .iter()
+            .map(|lake| (lake.sink, lake.start, lake.end))
+            .collect::<Vec<_>>();
+        assert_eq!(summary, vec![(1, 0, 0), (2, 3, 3), (3, 6, 6)]);
+        let surfaces = lakes.iter().map(|lake| lake.surface).collect::<Vec<_>>();
+        assert_slice_approx_eq_with_epsilon(surfaces.as_slice(), &[4.0, 4.0, 4.0], 0.1);
+        let volumes = lakes.iter().map(|lake| lake.volume).collect::<Vec<_>>();
+        assert_slice_approx_eq_with_epsilon(volumes.as_slice(), &[3.0, 1.0, 3.0], 0.1);
+    }
+
+    #[test]
+    fn water_flow_rain_with_single_precision() {
+        let landscape = vec![4.0, 7.0, 5.0, 8.0, 6.0, 9.0, 7.0];
+        let mut water_flow = WaterFlow::<f32, f32>::new(landscape);
+
+        water_flow.rain(2.0);
+
+        assert_slice_approx_eq_with_epsilon(
+            water_flow.total_levels().as_slice(),
+            &[8.4, 8.4, 8.4, 8.4, 8.4, 9.0, 9.0],
+            0.1,
+        );
+    }
+
+    #[test]
+    fn water_flow_lakes_with_small_heights() {
+        let mut water_flow = WaterFlow::<u16, f64>::new(vec![2, 6, 5, 9]);
+
+        water_flow.rain(2.0);
+
+        let lakes = water_flow.lakes();
+        assert_eq!(lakes.len(), 1);
+        assert_eq!(
+            lakes[0].spill.as_ref().map(|spill| spill.elevation),
+            Some(9u16)
+        );
+        assert_approx_eq!(lakes[0].surface, 7.0, 0.1);
+    }
+
+    #[test]
+    fn water_flow_rain_with_rational_volumes() {
+        let rational = |values: &[i128]| {
+            values
+                .iter()
+                .map(|value| Rational::new(*value, 5))
+                .collect::<Vec<_>>()
+        };
+
+        let mut water_flow = WaterFlow::<SegmentLevel, Rational>::new(vec![4, 7, 5, 8, 6, 9, 7]);
+        water_flow.rain(2.0);
+        assert_eq!(water_flow.water, rational(&[22, 7, 17, 2, 12, 0, 10]));
+
+        let mut water_flow = WaterFlow::<SegmentLevel, Rational>::new(vec![1, 4, 4, 3, 4, 4, 1]);
+        water_flow.rain(1.0);
+        assert_eq!(water_flow.water, rational(&[15, 0, 0, 5, 0, 0, 15]));
+
+        let landscape = vec![6, 4, 5, 9, 9, 2, 6, 5, 9, 7];
+        let mut water_flow = WaterFlow::<SegmentLevel, Rational>::new(landscape);
+        water_flow.rain(3.0);
+        let volume = water_flow
+            .water
+            .iter()
+            .fold(Rational::ZERO, |accum, water| accum + *water);
+        assert_eq!(volume, Rational::new(30, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "the heights of the landscape must be finite")]
+    fn water_flow_new_with_nan_heights() {
+        WaterFlow::<f64, f64>::new(vec![1.0, f64::NAN, 2.0]);
+    }
+
+    #[cfg(feature = "fixed-point")]
+    #[test]
+    fn water_flow_rain_with_fixed_point_volumes() {
+        use crate::fixed_point::Fixed;
+
+        let landscape = vec![4, 7, 5, 8, 6, 9, 7];
+        let mut water_flow = WaterFlow::<SegmentLevel, Fixed>::new(landscape.clone());
+        let mut other_water_flow = WaterFlow::<SegmentLevel, Fixed>::new(landscape);
+
+        water_flow.rain(2.0);
+        other_water_flow.rain(1.0);
+        other_water_flow.rain(2.0);
+
+        assert_eq!(water_flow.water, other_water_flow.water);
+        assert_slice_approx_eq_with_epsilon(
+            water_flow.total_levels().as_slice(),
+            &[8.4, 8.4, 8.4, 8.4, 8.4, 9.0, 9.0],
+            0.1,
+        );
+    }
+
     #[test]
     fn water_flow_rain_total_volume_is_conserved_within_an_error_interval() {
         let mut rng = thread_rng();
@@ -676,7 +1064,7 @@ mod tests {
                 landscape.push(rng.gen_range(0..20))
             }
 
-            let mut water_flow = WaterFlow::new(landscape);
+            let mut water_flow: WaterFlow = WaterFlow::new(landscape);
 
             let hours = rng.gen_range(1..10) as f64;
             water_flow.rain(hours);